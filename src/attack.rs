@@ -0,0 +1,115 @@
+//! Deliberate attacks on a copy of a chain, for showing what validation
+//! catches. Nothing here touches the chain it is given.
+
+use crate::block::Block;
+use crate::blockchain::{Blockchain, MINING_REWARD};
+use crate::display::short;
+use crate::transaction::{PublicKey, Transaction};
+use crate::wallet::Wallet;
+use anyhow::{Context, Result};
+
+/// A copy of a chain with one attack applied, and what the attack changed.
+#[derive(Debug)]
+pub struct Attack {
+    pub chain: Blockchain,
+    /// The block the attack altered or added.
+    pub height: u64,
+    pub before: String,
+    pub after: String,
+}
+
+/// Raises the amount of the most recent confirmed transfer without
+/// re-signing it, as someone editing `chain.json` by hand would.
+pub fn tamper_amount(blockchain: &Blockchain) -> Result<Attack> {
+    let mut chain = blockchain.clone();
+    let (height, tx) = chain
+        .chain
+        .iter_mut()
+        .rev()
+        .find_map(|block| {
+            let index = block.index;
+            block.transactions.iter_mut().find(|tx| tx.source.is_some()).map(|tx| (index, tx))
+        })
+        .context("tamper-amount needs a confirmed transfer to edit, but this chain only has mining rewards.")?;
+    let before = format!("Transaction {} in block #{} sends {} coins.", short(&tx.txid()), height, tx.amount);
+    tx.amount = tx.amount.saturating_mul(10).max(1000);
+    let after = format!("The same transaction now sends {} coins, under the original signature.", tx.amount);
+    Ok(Attack {
+        chain,
+        height,
+        before,
+        after,
+    })
+}
+
+/// Mines a fresh attacker some coins, then mines a block that spends them
+/// twice, to two different merchants.
+pub fn double_spend(blockchain: &Blockchain) -> Result<Attack> {
+    let mut chain = blockchain.clone();
+    let attacker = Wallet::new();
+    let attacker_address = PublicKey(attacker.public_key);
+    mine_onto(&mut chain, vec![Transaction::new_coinbase(attacker_address.clone(), MINING_REWARD)]);
+    let spends = vec![
+        Transaction::new_coinbase(PublicKey(Wallet::new().public_key), MINING_REWARD),
+        Transaction::new(&attacker, PublicKey(Wallet::new().public_key), MINING_REWARD, 0),
+        Transaction::new(&attacker, PublicKey(Wallet::new().public_key), MINING_REWARD, 1),
+    ];
+    let height = mine_onto(&mut chain, spends);
+    Ok(Attack {
+        chain,
+        height,
+        before: format!(
+            "Attacker {} mines block #{} and owns its {}-coin reward.",
+            short(&attacker_address.to_hex()),
+            height - 1,
+            MINING_REWARD
+        ),
+        after: format!(
+            "Block #{} pays those {} coins to two different merchants.",
+            height, MINING_REWARD
+        ),
+    })
+}
+
+/// Mines a block that moves the richest address's coins to an attacker,
+/// signed with the attacker's own key instead of the owner's.
+pub fn forge_signature(blockchain: &Blockchain) -> Result<Attack> {
+    let mut chain = blockchain.clone();
+    let victim = chain
+        .addresses()
+        .into_iter()
+        .filter(|summary| summary.balance > 0)
+        .max_by_key(|summary| summary.balance)
+        .context("forge-signature needs an address with coins to steal, but every balance is zero.")?;
+    let attacker = Wallet::new();
+    let attacker_address = PublicKey(attacker.public_key);
+    let nonce = chain.next_nonce(&victim.address);
+    let mut theft = Transaction::new(&attacker, attacker_address.clone(), victim.balance as u64, nonce);
+    theft.source = Some(victim.address.clone());
+    let height = mine_onto(
+        &mut chain,
+        vec![Transaction::new_coinbase(attacker_address.clone(), MINING_REWARD), theft],
+    );
+    Ok(Attack {
+        chain,
+        height,
+        before: format!("{} holds {} coins.", short(&victim.address.to_hex()), victim.balance),
+        after: format!(
+            "Block #{} sends them to {}, signed with the attacker's key instead of the owner's.",
+            height,
+            short(&attacker_address.to_hex())
+        ),
+    })
+}
+
+/// Mines `transactions` into a new block on top of `chain` with valid
+/// proof-of-work, skipping every other check. Returns its height.
+fn mine_onto(chain: &mut Blockchain, transactions: Vec<Transaction>) -> u64 {
+    let tip = chain.chain.last().unwrap();
+    let height = tip.index + 1;
+    let mut block = Block::new(height, transactions, tip.hash.clone(), chain.difficulty);
+    block.version = block.version.max(chain.required_block_version(height));
+    block.mine();
+    chain.chain.push(block);
+    height
+}
@@ -0,0 +1,125 @@
+use crate::wallet::StoredWallet;
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+/// Marks a file as a wallet backup, so other JSON isn't mistaken for one.
+pub const BACKUP_FORMAT: &str = "mini-blockchain-wallets";
+
+/// Most bytes a compressed payload may expand to. Wallet files are tiny, so
+/// anything near this is a decompression bomb rather than a real backup.
+pub const MAX_BACKUP_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Every wallet, as written by `wallet backup-all`. The payload is the
+/// wallet files as JSON (name to wallet), gzipped if `compressed`, then
+/// base64. Encrypted wallets stay encrypted inside it.
+/// `sha256` covers the payload bytes, so corruption in transfer is caught
+/// before anything is restored.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WalletBackup {
+    pub format: String,
+    pub compressed: bool,
+    /// Always false for now; kept so the format can say so once it isn't.
+    pub encrypted: bool,
+    /// Hex SHA-256 of the decoded payload bytes.
+    pub sha256: String,
+    pub payload: String,
+}
+
+impl WalletBackup {
+    pub fn seal(wallets: &BTreeMap<String, StoredWallet>, compress: bool) -> Result<Self> {
+        let mut bytes = serde_json::to_vec(wallets)?;
+        if compress {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&bytes)?;
+            bytes = encoder.finish()?;
+        }
+        Ok(WalletBackup {
+            format: BACKUP_FORMAT.to_string(),
+            compressed: compress,
+            encrypted: false,
+            sha256: hex::encode(Sha256::digest(&bytes)),
+            payload: STANDARD.encode(&bytes),
+        })
+    }
+
+    /// Checks the format and checksum, then hands back the wallets.
+    pub fn open(&self) -> Result<BTreeMap<String, StoredWallet>> {
+        self.open_within(MAX_BACKUP_BYTES)
+    }
+
+    fn open_within(&self, max_bytes: u64) -> Result<BTreeMap<String, StoredWallet>> {
+        if self.format != BACKUP_FORMAT {
+            bail!("That file isn't a wallet backup (format '{}').", self.format);
+        }
+        if self.encrypted {
+            bail!("That backup is encrypted, which this build can't read.");
+        }
+        let bytes = STANDARD
+            .decode(&self.payload)
+            .context("The backup's payload is damaged: it isn't valid base64.")?;
+        if hex::encode(Sha256::digest(&bytes)) != self.sha256 {
+            bail!("The backup's checksum doesn't match its contents. It was corrupted or altered.");
+        }
+        let json = if self.compressed {
+            let mut json = Vec::new();
+            GzDecoder::new(bytes.as_slice())
+                .take(max_bytes + 1)
+                .read_to_end(&mut json)
+                .context("The backup's payload is damaged: it doesn't decompress.")?;
+            if json.len() as u64 > max_bytes {
+                bail!("The backup's payload expands to more than {} bytes. Refusing to restore it.", max_bytes);
+            }
+            json
+        } else {
+            bytes
+        };
+        serde_json::from_slice(&json).context("The backup's checksum matches, but its wallets can't be read.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::seeded_wallet;
+
+    fn wallets() -> BTreeMap<String, StoredWallet> {
+        (1..=2)
+            .map(|seed| (format!("wallet{}", seed), StoredWallet::Plain(seeded_wallet(seed))))
+            .collect()
+    }
+
+    #[test]
+    fn backups_open_to_the_sealed_wallets() {
+        for compress in [false, true] {
+            let backup = WalletBackup::seal(&wallets(), compress).unwrap();
+            assert_eq!(backup.open().unwrap(), wallets());
+        }
+    }
+
+    #[test]
+    fn corrupted_backups_are_refused_by_the_checksum() {
+        for compress in [false, true] {
+            let mut backup = WalletBackup::seal(&wallets(), compress).unwrap();
+            let mut bytes = STANDARD.decode(&backup.payload).unwrap();
+            let middle = bytes.len() / 2;
+            bytes[middle] ^= 1;
+            backup.payload = STANDARD.encode(&bytes);
+            let err = backup.open().unwrap_err();
+            assert!(err.to_string().contains("checksum doesn't match"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn payloads_that_expand_too_far_are_refused() {
+        let backup = WalletBackup::seal(&wallets(), true).unwrap();
+        let size = serde_json::to_vec(&wallets()).unwrap().len() as u64;
+        assert!(backup.open_within(size).is_ok());
+        let err = backup.open_within(size - 1).unwrap_err();
+        assert!(err.to_string().contains("expands to more than"), "{}", err);
+    }
+}
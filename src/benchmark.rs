@@ -0,0 +1,56 @@
+use crate::block::Block;
+use crate::transaction::{PublicKey, Transaction};
+use crate::wallet::Wallet;
+use p256::ecdsa::signature::hazmat::PrehashVerifier;
+use sha2::{Digest, Sha256};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// Operations per second measured by `run`.
+#[derive(Debug, Clone, Copy)]
+pub struct BenchmarkResults {
+    /// Block hashes, the unit of work when mining.
+    pub hashes_per_second: f64,
+    /// Transaction signatures created.
+    pub signs_per_second: f64,
+    /// Transaction signatures checked, the bulk of validation.
+    pub verifies_per_second: f64,
+}
+
+/// Runs each micro-benchmark for roughly `duration` and reports throughput.
+pub fn run(duration: Duration) -> BenchmarkResults {
+    let wallet = Wallet::new();
+    let tx = Transaction::new(&wallet, PublicKey(wallet.public_key), 1, 0);
+    let mut block = Block::new(1, vec![tx], "0".repeat(64), 0);
+    let hashes_per_second = measure(duration, || {
+        block.nonce += 1;
+        black_box(block.calculate_hash());
+    });
+
+    let digest = Sha256::digest(b"benchmark");
+    let signs_per_second = measure(duration, || {
+        black_box(wallet.sign_prehashed(black_box(&digest)));
+    });
+
+    let signature = wallet.sign_prehashed(&digest);
+    let verifies_per_second = measure(duration, || {
+        let _ = black_box(wallet.public_key.verify_prehash(black_box(&digest), &signature));
+    });
+
+    BenchmarkResults {
+        hashes_per_second,
+        signs_per_second,
+        verifies_per_second,
+    }
+}
+
+/// Calls `op` repeatedly until `duration` has passed; returns calls per second.
+fn measure(duration: Duration, mut op: impl FnMut()) -> f64 {
+    let start = Instant::now();
+    let mut count = 0u64;
+    while count == 0 || start.elapsed() < duration {
+        op();
+        count += 1;
+    }
+    count as f64 / start.elapsed().as_secs_f64()
+}
@@ -1,20 +1,100 @@
+use crate::display::short;
+use crate::merkle;
 use crate::transaction::Transaction;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Format version written into every newly created block. Version 2 added
+/// `merkle_root` and hashes the version itself, so it can't be raised or
+/// lowered without re-mining the block. Version 3 requires every transfer
+/// to carry a nonce and a low-S signature.
+pub const BLOCK_VERSION: u32 = 3;
+
+/// Hashes tried between calls to the `Block::mine_with_checkpoints` callback.
+pub const CHECKPOINT_INTERVAL: u64 = 100_000;
+
+/// Files written before blocks and chains carried a version are version 1.
+pub(crate) fn legacy_version() -> u32 {
+    1
+}
+
+/// Proof-of-work performance for a single `Block::mine` run.
+#[derive(Debug, Clone, Copy)]
+pub struct MiningStats {
+    /// Hashes computed, i.e. nonces tried, including the winning one.
+    pub hashes: u64,
+    pub duration: Duration,
+    pub difficulty: usize,
+}
+
+impl MiningStats {
+    pub fn hashes_per_second(&self) -> f64 {
+        let secs = self.duration.as_secs_f64();
+        if secs > 0.0 {
+            self.hashes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Block {
+    #[serde(default = "legacy_version")]
+    pub version: u32,
     pub index: u64,
     pub timestamp: i64,
     pub transactions: Vec<Transaction>,
+    /// Root of the Merkle tree of the transactions' ids, hashed in place of
+    /// the transactions themselves. Required from version 2; empty in
+    /// version 1 blocks, which hash the full transaction list instead.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub merkle_root: String,
     pub previous_hash: String,
     pub hash: String,
     pub nonce: u64,
     pub difficulty: usize,
 }
 
+/// Everything a version 2+ block hashes, with the transactions represented
+/// by their Merkle root: enough to check the block's hash and proof-of-work,
+/// and a Merkle proof against it, without the transactions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub version: u32,
+    pub index: u64,
+    pub timestamp: i64,
+    pub merkle_root: String,
+    pub previous_hash: String,
+    pub hash: String,
+    pub nonce: u64,
+    pub difficulty: usize,
+}
+
+impl BlockHeader {
+    /// Whether `hash` matches the header. Version 1 blocks don't hash a
+    /// header, so theirs never verifies.
+    pub fn verify_hash(&self) -> bool {
+        let data = header_hash_data(
+            self.version,
+            self.index,
+            self.timestamp,
+            &self.merkle_root,
+            &self.previous_hash,
+            self.nonce,
+            self.difficulty,
+        );
+        self.version >= 2 && self.hash == format!("{:x}", Sha256::digest(data))
+    }
+
+    pub fn has_valid_pow(&self) -> bool {
+        self.hash.starts_with(&"0".repeat(self.difficulty))
+    }
+}
+
 impl Display for Block {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         let tx_list = self
@@ -26,15 +106,15 @@ impl Display for Block {
 
         write!(
             f,
-            "Block #{}\n----------------\nTimestamp: {}\nDifficulty: {}\nTransactions:\n{}\n\nPrev. Hash: {}...\n      Hash: {}...\n     Nonce: {}\n",
+            "Block #{}\n----------------\nTimestamp: {}\nDifficulty: {}\nTransactions:\n{}\n\nPrev. Hash: {}\n      Hash: {}\n     Nonce: {}\n",
             self.index,
             DateTime::from_timestamp(self.timestamp, 0)
                 .map(|dt| dt.to_rfc2822())
                 .unwrap_or_default(),
             self.difficulty,
             tx_list,
-            &self.previous_hash[..10],
-            &self.hash[..10],
+            short(&self.previous_hash),
+            short(&self.hash),
             self.nonce
         )
     }
@@ -47,10 +127,13 @@ impl Block {
         previous_hash: String,
         difficulty: usize,
     ) -> Self {
+        let merkle_root = merkle::merkle_root(&txids(&transactions));
         Block {
+            version: BLOCK_VERSION,
             index,
             timestamp: Utc::now().timestamp(),
             transactions,
+            merkle_root,
             previous_hash,
             hash: String::new(),
             nonce: 0,
@@ -58,31 +141,262 @@ impl Block {
         }
     }
 
-    pub fn mine(&mut self) {
+    pub fn mine(&mut self) -> MiningStats {
+        self.mine_with_checkpoints(|_| {})
+    }
+
+    /// Like `mine`, but hands the block to `on_checkpoint` every
+    /// `CHECKPOINT_INTERVAL` hashes, so an interrupted mine can be resumed
+    /// from the saved nonce.
+    pub fn mine_with_checkpoints(&mut self, mut on_checkpoint: impl FnMut(&Block)) -> MiningStats {
         let prefix = "0".repeat(self.difficulty);
+        let started = Instant::now();
+        let mut hashes = 0u64;
         loop {
-            let hash_data = self.prepare_hash_data();
-            let mut hasher = Sha256::new();
-            hasher.update(hash_data);
-            let new_hash = format!("{:x}", hasher.finalize());
+            let new_hash = self.calculate_hash();
+            hashes += 1;
 
             if new_hash.starts_with(&prefix) {
                 self.hash = new_hash;
-                return;
+                return MiningStats {
+                    hashes,
+                    duration: started.elapsed(),
+                    difficulty: self.difficulty,
+                };
             }
             self.nonce += 1;
+            if hashes.is_multiple_of(CHECKPOINT_INTERVAL) {
+                on_checkpoint(self);
+            }
         }
     }
 
+    /// Whether `other` is the same unmined block as this one (same position,
+    /// parent, difficulty and transactions), so its nonce can be carried over.
+    pub fn is_same_candidate(&self, other: &Block) -> bool {
+        self.version == other.version
+            && self.index == other.index
+            && self.previous_hash == other.previous_hash
+            && self.difficulty == other.difficulty
+            && self.transactions.len() == other.transactions.len()
+            && self
+                .transactions
+                .iter()
+                .zip(&other.transactions)
+                .all(|(a, b)| a.txid() == b.txid())
+    }
+
+    /// Hashes the block's contents (everything except `hash` itself).
+    pub fn calculate_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.prepare_hash_data());
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Whether the stored `hash` matches the block's current contents, i.e.
+    /// nothing hashed has been changed since it was mined. When the block has a
+    /// Merkle root, that includes the root matching its transactions.
+    pub fn verify_hash(&self) -> bool {
+        let root_optional = self.version < 2 && self.merkle_root.is_empty();
+        self.hash == self.calculate_hash() && (root_optional || self.merkle_root == self.compute_merkle_root())
+    }
+
+    /// Merkle root of the block's transactions as they are now.
+    pub fn compute_merkle_root(&self) -> String {
+        merkle::merkle_root(&txids(&self.transactions))
+    }
+
+    /// Proof that transaction `tx_index` is in this block, checkable with
+    /// `merkle::verify_merkle_proof` against `merkle_root`. `None` if the
+    /// block has no such transaction.
+    pub fn merkle_proof(&self, tx_index: usize) -> Option<Vec<String>> {
+        merkle::merkle_proof(&txids(&self.transactions), tx_index)
+    }
+
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            version: self.version,
+            index: self.index,
+            timestamp: self.timestamp,
+            merkle_root: self.merkle_root.clone(),
+            previous_hash: self.previous_hash.clone(),
+            hash: self.hash.clone(),
+            nonce: self.nonce,
+            difficulty: self.difficulty,
+        }
+    }
+
+    /// Whether the block's transaction list is one its Merkle root pins
+    /// down. The tree pairs an odd last node with itself, so repeating the
+    /// last transactions keeps the root (and hash) unchanged; version 2+
+    /// blocks therefore may not list a transaction twice. Version 1 blocks
+    /// hash the full list, and some legitimately hold identical payments.
+    pub fn has_unambiguous_transactions(&self) -> bool {
+        let mut seen = std::collections::HashSet::new();
+        self.version < 2 || self.transactions.iter().all(|tx| seen.insert(tx.txid()))
+    }
+
+    /// Whether the stored `hash` has the leading zeros its `difficulty`
+    /// demands, i.e. the block was actually mined.
+    pub fn has_valid_pow(&self) -> bool {
+        self.hash.starts_with(&"0".repeat(self.difficulty))
+    }
+
+    /// Expected number of hashes needed to mine this block: each leading hex
+    /// zero makes a valid hash 16 times rarer.
+    pub fn work(&self) -> u128 {
+        1u128.checked_shl(4 * self.difficulty as u32).unwrap_or(u128::MAX)
+    }
+
     fn prepare_hash_data(&self) -> String {
-        serde_json::to_string(&(
-            &self.index,
-            &self.timestamp,
-            &self.transactions,
-            &self.previous_hash,
-            &self.nonce,
-            &self.difficulty,
-        ))
+        if self.version >= 2 {
+            return header_hash_data(
+                self.version,
+                self.index,
+                self.timestamp,
+                &self.merkle_root,
+                &self.previous_hash,
+                self.nonce,
+                self.difficulty,
+            );
+        }
+        if !self.merkle_root.is_empty() {
+            serde_json::to_string(&(
+                &self.index,
+                &self.timestamp,
+                &self.merkle_root,
+                &self.previous_hash,
+                &self.nonce,
+                &self.difficulty,
+            ))
+        } else {
+            serde_json::to_string(&(
+                &self.index,
+                &self.timestamp,
+                &self.transactions,
+                &self.previous_hash,
+                &self.nonce,
+                &self.difficulty,
+            ))
+        }
         .unwrap()
     }
-}
\ No newline at end of file
+}
+
+/// What a version 2+ block (or its `BlockHeader`) hashes.
+fn header_hash_data(
+    version: u32,
+    index: u64,
+    timestamp: i64,
+    merkle_root: &str,
+    previous_hash: &str,
+    nonce: u64,
+    difficulty: usize,
+) -> String {
+    serde_json::to_string(&(version, index, timestamp, merkle_root, previous_hash, nonce, difficulty)).unwrap()
+}
+
+fn txids(transactions: &[Transaction]) -> Vec<String> {
+    transactions.iter().map(Transaction::txid).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::PublicKey;
+    use crate::wallet::Wallet;
+
+    fn mined_block(version: u32) -> Block {
+        let coinbase = Transaction::new_coinbase(PublicKey(Wallet::new().public_key), 100);
+        let mut block = Block::new(1, vec![coinbase], "0".repeat(64), 1);
+        block.version = version;
+        if version < 2 {
+            block.merkle_root.clear();
+        }
+        block.mine();
+        block
+    }
+
+    #[test]
+    fn mined_blocks_verify() {
+        for version in [1, 2, 3] {
+            let block = mined_block(version);
+            assert!(block.verify_hash() && block.has_valid_pow(), "version {}", version);
+        }
+    }
+
+    #[test]
+    fn editing_a_mined_block_breaks_its_hash() {
+        for version in [1, 2] {
+            let mut amount = mined_block(version);
+            amount.transactions[0].amount += 1;
+            assert!(!amount.verify_hash(), "amount, version {}", version);
+
+            let mut timestamp = mined_block(version);
+            timestamp.timestamp += 1;
+            assert!(!timestamp.verify_hash(), "timestamp, version {}", version);
+
+            let mut added = mined_block(version);
+            let extra = added.transactions[0].clone();
+            added.transactions.insert(0, Transaction { amount: 1, ..extra });
+            assert!(!added.verify_hash(), "added transaction, version {}", version);
+
+            let mut hash = mined_block(version);
+            hash.hash = "0".repeat(64);
+            assert!(hash.has_valid_pow() && !hash.verify_hash(), "hash, version {}", version);
+        }
+    }
+
+    #[test]
+    fn stored_merkle_root_must_match_the_transactions() {
+        let mut block = mined_block(2);
+        block.transactions[0].amount += 1;
+        block.merkle_root = block.compute_merkle_root();
+        assert!(!block.verify_hash());
+    }
+
+    #[test]
+    fn changing_the_version_breaks_the_hash() {
+        for (mined, edited) in [(2, 3), (3, 2), (2, 1), (1, 2)] {
+            let mut block = mined_block(mined);
+            block.version = edited;
+            assert!(!block.verify_hash(), "version {} edited to {}", mined, edited);
+        }
+    }
+
+    #[test]
+    fn repeated_transactions_keep_the_hash_but_are_refused() {
+        let wallet = Wallet::new();
+        let payee = PublicKey(Wallet::new().public_key);
+        let payments = (0..2).map(|nonce| Transaction::builder(payee.clone(), 5).nonce(nonce).sign(&wallet));
+        let mut transactions = vec![Transaction::new_coinbase(payee.clone(), 100)];
+        transactions.extend(payments);
+        let mut block = Block::new(1, transactions, "0".repeat(64), 1);
+        block.mine();
+        assert!(block.has_unambiguous_transactions());
+
+        let last = block.transactions[2].clone();
+        block.transactions.push(last);
+        assert!(block.verify_hash());
+        assert!(!block.has_unambiguous_transactions());
+    }
+
+    #[test]
+    fn header_verifies_like_its_block() {
+        let block = mined_block(2);
+        let header = block.header();
+        assert!(header.verify_hash() && header.has_valid_pow());
+        let mut altered = header.clone();
+        altered.merkle_root = "0".repeat(64);
+        assert!(!altered.verify_hash());
+        assert!(!mined_block(1).header().verify_hash());
+    }
+
+    #[test]
+    fn version_2_blocks_need_a_matching_merkle_root() {
+        let mut block = mined_block(2);
+        block.merkle_root.clear();
+        block.hash = block.calculate_hash();
+        assert!(!block.verify_hash());
+    }
+}
@@ -1,121 +1,1809 @@
-use crate::block::Block;
-use crate::transaction::{PublicKey, Transaction};
-use anyhow::{bail, Result};
+use crate::block::{legacy_version, Block, MiningStats};
+use crate::display::short;
+use crate::error::BlockchainError;
+use crate::ledger::Ledger;
+use crate::transaction::{PublicKey, SpendCondition, Transaction};
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 
-const MINING_REWARD: u64 = 100;
+pub const MINING_REWARD: u64 = 100;
 const DIFFICULTY_ADJUSTMENT_INTERVAL: u64 = 10;
 const TARGET_BLOCK_TIME_SECS: i64 = 30;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Largest serialized transaction `add_transaction` will accept.
+pub const MAX_TRANSACTION_BYTES: usize = 1024;
+/// Most transactions (including the coinbase) a single block may hold.
+pub const MAX_BLOCK_TRANSACTIONS: usize = 1000;
+
+/// Received amounts below this are dust: not worth the cost of spending.
+pub const DUST_THRESHOLD: u64 = 5;
+
+/// Deepest reorganization `replace_chain` accepts, in replaced local blocks.
+pub const MAX_REORG_DEPTH: u64 = 100;
+
+/// The default network, whose data lives directly in the app directory.
+pub const MAIN_NETWORK: &str = "main";
+
+/// Coins the coinbase of the block at `height` pays its miner. Flat for
+/// now; the one place to change if rewards ever halve.
+pub fn block_reward(_height: u64) -> u64 {
+    MINING_REWARD
+}
+
+fn main_network() -> String {
+    MAIN_NETWORK.to_string()
+}
+
+/// Blocks below the validation watermark that `validate` re-checks anyway.
+pub const VALIDATION_OVERLAP: u64 = 6;
+
+/// Format version of the chain envelope written by this build.
+pub const CHAIN_VERSION: u32 = 1;
+
+/// Where a transaction was found by `Blockchain::find_transaction`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxLocation {
+    /// Confirmed in the block at this height.
+    Block(u64),
+    /// Still waiting in the mempool.
+    Mempool,
+}
+
+/// Criteria for `Blockchain::search_transactions`; unset fields match anything.
+#[derive(Debug, Clone, Default)]
+pub struct TxFilter {
+    pub from: Option<PublicKey>,
+    pub to: Option<PublicKey>,
+    pub min_amount: Option<u64>,
+    pub max_amount: Option<u64>,
+    /// Only mining rewards and premine allocations.
+    pub coinbase_only: bool,
+}
+
+impl TxFilter {
+    pub fn matches(&self, tx: &Transaction) -> bool {
+        self.from.as_ref().is_none_or(|from| tx.source.as_ref() == Some(from))
+            && self.to.as_ref().is_none_or(|to| tx.destination == *to)
+            && self.min_amount.is_none_or(|min| tx.amount >= min)
+            && self.max_amount.is_none_or(|max| tx.amount <= max)
+            && (!self.coinbase_only || tx.source.is_none())
+    }
+}
+
+/// Result of `Blockchain::submit_transaction`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Submission {
+    /// The transaction was new and is now in the mempool.
+    Added(String),
+    /// The key was already used by this sender; nothing was added.
+    AlreadySubmitted(String),
+}
+
+/// One address seen on-chain, as listed by `Blockchain::addresses`.
+#[derive(Debug, Clone)]
+pub struct AddressSummary {
+    pub address: PublicKey,
+    /// Height of the first block the address appears in.
+    pub first_seen: u64,
+    pub balance: i64,
+}
+
+/// What happened to local transactions when `replace_chain` switched chains.
+#[derive(Debug, Default)]
+pub struct ReorgReport {
+    /// Height of the first block that differs between the old and new chain.
+    pub fork_height: u64,
+    /// Orphaned transactions that were put back into the mempool.
+    pub reinjected: Vec<String>,
+    /// Orphaned transactions that no longer validate, with the reason.
+    pub dropped: Vec<(String, String)>,
+}
+
+/// Outcome of `import_transactions`.
+#[derive(Debug, Default)]
+pub struct TxImport {
+    /// Ids of the transactions added to the mempool.
+    pub accepted: Vec<String>,
+    /// Transactions that were refused, with the reason.
+    pub rejected: Vec<(String, String)>,
+}
+
+/// Activity over the most recent blocks, from `Blockchain::rate`.
+#[derive(Debug, Clone, Copy)]
+pub struct ChainRate {
+    /// Blocks in the window.
+    pub blocks: usize,
+    /// Seconds between the block before the window and the tip.
+    pub elapsed_secs: i64,
+    /// Expected hashes needed to mine the window's blocks.
+    pub work: u128,
+    /// Non-coinbase transactions in the window.
+    pub transactions: usize,
+}
+
+impl ChainRate {
+    pub fn average_interval_secs(&self) -> f64 {
+        self.elapsed_secs as f64 / self.blocks as f64
+    }
+
+    /// Hash rate the network needed to produce the window's blocks in the time it took.
+    pub fn hashes_per_second(&self) -> f64 {
+        self.per_second(self.work as f64)
+    }
+
+    pub fn transactions_per_block(&self) -> f64 {
+        self.transactions as f64 / self.blocks as f64
+    }
+
+    pub fn transactions_per_second(&self) -> f64 {
+        self.per_second(self.transactions as f64)
+    }
+
+    fn per_second(&self, amount: f64) -> f64 {
+        if self.elapsed_secs > 0 {
+            amount / self.elapsed_secs as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+/// One completed difficulty-adjustment interval, from `Blockchain::difficulty_history`.
+#[derive(Debug, Clone, Copy)]
+pub struct RetargetPeriod {
+    /// First and last block of the interval; the last is where retargeting happens.
+    pub start: u64,
+    pub end: u64,
+    /// Seconds between the `start` and `end` blocks.
+    pub time_taken: i64,
+    pub expected_time: i64,
+    /// Difficulty of the `end` block, and of the block after it (or what
+    /// the rule gives for it if it hasn't been mined yet).
+    pub difficulty_before: usize,
+    pub difficulty_after: usize,
+}
+
+/// A staged protocol upgrade: blocks at `height` and above must have at
+/// least `min_block_version`, while earlier blocks keep the old rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionActivation {
+    pub height: u64,
+    pub min_block_version: u32,
+}
+
+/// A chain's starting point as written by `genesis-export`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenesisExport {
+    pub network: String,
+    /// The genesis block exactly as mined, premine allocations and timestamp included.
+    pub block: Block,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Blockchain {
+    #[serde(default = "legacy_version")]
+    pub version: u32,
+    /// Which network this chain belongs to; chains from before networks existed are `main`.
+    #[serde(default = "main_network")]
+    pub network: String,
     pub chain: Vec<Block>,
     pub mempool: Vec<Transaction>,
     pub difficulty: usize,
+    /// Transaction ids submitted under an idempotency key, keyed by `<sender>:<key>`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub idempotency_keys: HashMap<String, String>,
+    /// Minimum block version from an activation height onwards, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_activation: Option<VersionActivation>,
+    /// Height up to which the chain last passed validation (0 = never).
+    /// Stored outside the chain file, see `config::save_app_state`.
+    #[serde(skip)]
+    pub validated_height: u64,
+    /// Blocks the rewards of blocks mined here stay locked for (0 = none).
+    /// A local setting, taken from `Config::coinbase_maturity`; the lock
+    /// itself is a `SpendCondition` on each coinbase, so it outlives it.
+    #[serde(skip)]
+    pub coinbase_maturity: u64,
 }
 
 impl Blockchain {
     pub fn new() -> Result<Self> {
-        let mut genesis_block = Block::new(0, vec![], "0".to_string(), 2);
+        Self::new_on_network(MAIN_NETWORK)
+    }
+
+    /// Creates a fresh chain for the named network. The network is baked into
+    /// the genesis block, so chains from different networks never share a chain id.
+    pub fn new_on_network(network: &str) -> Result<Self> {
+        Self::new_with_premine(network, &[])
+    }
+
+    /// Like `new_on_network`, but the genesis block also pays each
+    /// `(address, amount)` pair, giving those addresses a starting balance.
+    pub fn new_with_premine(network: &str, premine: &[(PublicKey, u64)]) -> Result<Self> {
+        let genesis_previous_hash = genesis_previous_hash(network);
+        let allocations = premine
+            .iter()
+            .map(|(address, amount)| Transaction::new_coinbase(address.clone(), *amount))
+            .collect();
+        let mut genesis_block = Block::new(0, allocations, genesis_previous_hash, 2);
+        // Rejects allocations whose total overflows a balance.
+        Ledger::replay([&genesis_block])?;
         genesis_block.mine();
+        Ok(Self::with_genesis(network, genesis_block))
+    }
 
-        Ok(Blockchain {
+    fn with_genesis(network: &str, genesis_block: Block) -> Self {
+        Blockchain {
+            version: CHAIN_VERSION,
+            network: network.to_string(),
+            difficulty: genesis_block.difficulty,
             chain: vec![genesis_block],
             mempool: vec![],
-            difficulty: 2,
-        })
+            idempotency_keys: HashMap::new(),
+            version_activation: None,
+            validated_height: 0,
+            coinbase_maturity: 0,
+        }
+    }
+
+    /// This chain's genesis block and network, for `from_genesis`.
+    pub fn genesis_export(&self) -> GenesisExport {
+        GenesisExport {
+            network: self.network.clone(),
+            block: self.chain[0].clone(),
+        }
+    }
+
+    /// A fresh chain starting from an exported genesis block. The block is
+    /// used exactly as exported, timestamp included, so every chain built
+    /// from the same export has the same chain id.
+    pub fn from_genesis(export: GenesisExport) -> Result<Self> {
+        let block = export.block;
+        if block.index != 0 || block.previous_hash != genesis_previous_hash(&export.network) {
+            bail!("That isn't a genesis block for the '{}' network.", export.network);
+        }
+        if !block.verify_hash() || !block.has_valid_pow() {
+            bail!("The genesis block's hash doesn't match its contents or doesn't meet its difficulty.");
+        }
+        if block.transactions.iter().any(|tx| tx.source.is_some()) {
+            bail!("A genesis block may only contain premine allocations.");
+        }
+        Ledger::replay([&block])?;
+        Ok(Self::with_genesis(&export.network, block))
+    }
+
+    /// Upgrades a chain loaded from an older file format to `CHAIN_VERSION`.
+    pub fn migrate(&mut self) -> Result<()> {
+        if self.version > CHAIN_VERSION {
+            bail!(
+                "This chain was saved in format v{}, but this build only understands up to v{}. Please upgrade mini-blockchain.",
+                self.version,
+                CHAIN_VERSION
+            );
+        }
+        // v1 is the original layout, so there is nothing to rewrite yet. Future
+        // format changes add their upgrade steps here, oldest first.
+        self.version = CHAIN_VERSION;
+        Ok(())
+    }
+
+    /// Rejects chains whose blocks or transactions are larger than this build
+    /// would ever produce, e.g. a hand-crafted or untrusted `chain.json`.
+    pub fn check_limits(&self) -> Result<()> {
+        for block in &self.chain {
+            if block.transactions.len() > MAX_BLOCK_TRANSACTIONS {
+                bail!(
+                    "Block #{} has {} transactions, more than the limit of {}.",
+                    block.index,
+                    block.transactions.len(),
+                    MAX_BLOCK_TRANSACTIONS
+                );
+            }
+        }
+        for tx in self.chain.iter().flat_map(|b| &b.transactions).chain(&self.mempool) {
+            check_transaction_size(tx)?;
+        }
+        Ok(())
+    }
+
+    /// Like `add_transaction`, but retrying with the same sender and
+    /// `idempotency_key` returns the original transaction's id instead of
+    /// queueing a second payment.
+    pub fn submit_transaction(
+        &mut self,
+        transaction: Transaction,
+        idempotency_key: &str,
+    ) -> Result<Submission> {
+        let sender = transaction
+            .source
+            .as_ref()
+            .map(PublicKey::to_hex)
+            .unwrap_or_default();
+        let dedup_key = format!("{}:{}", sender, idempotency_key);
+        if let Some(txid) = self.idempotency_keys.get(&dedup_key) {
+            return Ok(Submission::AlreadySubmitted(txid.clone()));
+        }
+
+        let txid = transaction.txid();
+        self.add_transaction(transaction)?;
+        self.idempotency_keys.insert(dedup_key, txid.clone());
+        Ok(Submission::Added(txid))
     }
 
     pub fn add_transaction(&mut self, transaction: Transaction) -> Result<()> {
+        check_transaction_size(&transaction)?;
         if !transaction.is_valid() {
             bail!("Transaction has a bad signature. It's probably fraudulent.");
         }
+        if transaction.uses_degenerate_key() {
+            bail!("Transaction uses a degenerate key whose private key is public knowledge.");
+        }
+        if transaction.source.is_some() && transaction.nonce.is_none() {
+            bail!(BlockchainError::InvalidInput(
+                "Transaction has no nonce, so it could be replayed. Sign it again to get one.".to_string()
+            ));
+        }
+        if !transaction.has_low_s() {
+            bail!(BlockchainError::InvalidInput(
+                "Transaction's signature isn't in low-S form. It may be a modified copy of another transaction."
+                    .to_string()
+            ));
+        }
+        if let Some(network) = transaction.network.as_ref().filter(|network| **network != self.network) {
+            bail!(BlockchainError::InvalidInput(format!(
+                "That transaction was signed for the '{}' network, but this chain is on '{}'.",
+                network, self.network
+            )));
+        }
+        let txid = transaction.txid();
+        match self.find_transaction(&txid) {
+            Some((TxLocation::Mempool, _)) => bail!(BlockchainError::InvalidInput(format!(
+                "Transaction {} is already in the mempool.",
+                txid
+            ))),
+            Some((TxLocation::Block(height), _)) => bail!(BlockchainError::InvalidInput(format!(
+                "Transaction {} is already confirmed in block #{}.",
+                txid, height
+            ))),
+            None => {}
+        }
+        let mut ledger = Ledger::replay(&self.chain)?;
+        let next_height = self.chain.len() as u64;
+        for tx in self.mempool.iter().chain([&transaction]) {
+            ledger.apply(tx, next_height)?;
+        }
         self.mempool.push(transaction);
         Ok(())
     }
 
-    pub fn mine_pending_transactions(&mut self, miner_address: PublicKey) -> Result<()> {
+    /// Feeds each transaction through `add_transaction` in order. A rejected
+    /// transaction is recorded and skipped rather than stopping the import.
+    pub fn import_transactions(&mut self, transactions: Vec<Transaction>) -> TxImport {
+        let mut report = TxImport::default();
+        for tx in transactions {
+            let txid = tx.txid();
+            match self.add_transaction(tx) {
+                Ok(()) => report.accepted.push(txid),
+                Err(err) => report.rejected.push((txid, err.to_string())),
+            }
+        }
+        report
+    }
+
+    /// Re-checks every mempool transaction against the current chain, in
+    /// order, as `add_transaction` would, and drops those that no longer
+    /// pass (e.g. unfunded after a reorg) or are already confirmed. Returns
+    /// the dropped ids with the reason.
+    pub fn prune_mempool(&mut self) -> Vec<(String, String)> {
+        let confirmed: HashSet<String> = self
+            .chain
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .map(Transaction::txid)
+            .collect();
+        let mut dropped = Vec::new();
+        for tx in std::mem::take(&mut self.mempool) {
+            let txid = tx.txid();
+            if confirmed.contains(&txid) {
+                dropped.push((txid, "already confirmed in the chain".to_string()));
+            } else if let Err(err) = self.add_transaction(tx) {
+                dropped.push((txid, err.to_string()));
+            }
+        }
+        dropped
+    }
+
+    pub fn mine_pending_transactions(&mut self, miner_address: PublicKey) -> Result<MiningStats> {
+        self.mine_pending_transactions_resumable(miner_address, None, |_| {})
+    }
+
+    /// Like `mine_pending_transactions`, but continues from `checkpoint`'s
+    /// nonce if it is the same candidate block, and passes the block in
+    /// progress to `on_checkpoint` periodically so it can be saved.
+    pub fn mine_pending_transactions_resumable(
+        &mut self,
+        miner_address: PublicKey,
+        checkpoint: Option<&Block>,
+        on_checkpoint: impl FnMut(&Block),
+    ) -> Result<MiningStats> {
         if self.mempool.is_empty() {
             println!("[INFO] Mempool is empty. Mining a block with only the reward transaction.");
         }
 
-        let reward_tx = Transaction::new_coinbase(miner_address, MINING_REWARD);
+        let (transactions, remaining) = self.minable_transactions()?;
+        let stats = self.mine_block(miner_address, transactions, None, checkpoint, on_checkpoint)?;
+        self.mempool = remaining;
+        Ok(stats)
+    }
 
-        let mut transactions_for_block = self.mempool.clone();
-        transactions_for_block.insert(0, reward_tx);
+    /// Like `mine_pending_transactions`, but the block is timestamped exactly
+    /// `TARGET_BLOCK_TIME_SECS` after the tip instead of with the clock, so
+    /// the same chain and mempool always produce the same block.
+    pub fn mine_pending_transactions_deterministic(&mut self, miner_address: PublicKey) -> Result<MiningStats> {
+        let (transactions, remaining) = self.minable_transactions()?;
+        let timestamp = self.chain.last().unwrap().timestamp + TARGET_BLOCK_TIME_SECS;
+        let stats = self.mine_block(miner_address, transactions, Some(timestamp), None, |_| {})?;
+        self.mempool = remaining;
+        Ok(stats)
+    }
+
+    /// Splits the mempool into what the next block can include and what has
+    /// to wait: transactions whose locktime hasn't been reached, ones that
+    /// depend on those, and anything over the block cap (which leaves room
+    /// for the coinbase).
+    pub fn minable_transactions(&self) -> Result<(Vec<Transaction>, Vec<Transaction>)> {
+        let height = self.chain.len() as u64;
+        let mut ledger = Ledger::replay(&self.chain)?;
+        let mut included = Vec::new();
+        let mut waiting = Vec::new();
+        for tx in &self.mempool {
+            if included.len() < MAX_BLOCK_TRANSACTIONS - 1 && tx.is_final(height) && ledger.apply(tx, height).is_ok() {
+                included.push(tx.clone());
+            } else {
+                waiting.push(tx.clone());
+            }
+        }
+        Ok((included, waiting))
+    }
+
+    /// How many blocks `mine` needs to include the pending transaction
+    /// `txid` if no other transactions arrive: 1 means the next block. Each
+    /// simulated block takes the mempool in order up to the block-size cap,
+    /// like `minable_transactions`. `None` if it would never be mined as
+    /// things stand, e.g. because it is unfunded.
+    pub fn blocks_until_mined(&self, txid: &str) -> Result<Option<u64>> {
+        if !self.mempool.iter().any(|tx| tx.txid() == txid) {
+            bail!("Transaction {} isn't in the mempool.", txid);
+        }
+        let tip = self.chain.len() as u64;
+        let mut height = tip;
+        let mut ledger = Ledger::replay(&self.chain)?;
+        let mut pending: Vec<&Transaction> = self.mempool.iter().collect();
+        loop {
+            let mut included = 0;
+            let mut waiting = Vec::new();
+            for tx in pending {
+                if included < MAX_BLOCK_TRANSACTIONS - 1 && tx.is_final(height) && ledger.apply(tx, height).is_ok() {
+                    if tx.txid() == txid {
+                        return Ok(Some(height - tip + 1));
+                    }
+                    included += 1;
+                } else {
+                    waiting.push(tx);
+                }
+            }
+            pending = waiting;
+            height = if included > 0 {
+                height + 1
+            } else {
+                // Nothing fits yet; skip ahead to the next locktime, if any.
+                match pending.iter().filter_map(|tx| tx.locktime).filter(|&locktime| locktime > height).min() {
+                    Some(locktime) => locktime,
+                    None => return Ok(None),
+                }
+            };
+        }
+    }
+
+    /// Mines a block containing only the mempool transactions with the given ids,
+    /// leaving everything else pending.
+    pub fn mine_selected_transactions(
+        &mut self,
+        miner_address: PublicKey,
+        txids: &[String],
+    ) -> Result<MiningStats> {
+        for txid in txids {
+            if !self.mempool.iter().any(|tx| tx.txid() == *txid) {
+                bail!("Transaction {} isn't in the mempool.", txid);
+            }
+        }
+
+        if txids.len() > MAX_BLOCK_TRANSACTIONS - 1 {
+            bail!(
+                "A block can hold at most {} transactions besides the reward.",
+                MAX_BLOCK_TRANSACTIONS - 1
+            );
+        }
 
+        let (selected, remaining): (Vec<Transaction>, Vec<Transaction>) = self
+            .mempool
+            .iter()
+            .cloned()
+            .partition(|tx| txids.contains(&tx.txid()));
+        let stats = self.mine_block(miner_address, selected, None, None, |_| {})?;
+        self.mempool = remaining;
+        Ok(stats)
+    }
+
+    fn mine_block(
+        &mut self,
+        miner_address: PublicKey,
+        transactions: Vec<Transaction>,
+        timestamp: Option<i64>,
+        checkpoint: Option<&Block>,
+        on_checkpoint: impl FnMut(&Block),
+    ) -> Result<MiningStats> {
         self.adjust_difficulty();
+        let mut new_block = self.assemble_block(miner_address, transactions, self.difficulty)?;
+        if let Some(timestamp) = timestamp {
+            new_block.timestamp = timestamp;
+        }
+
+        match checkpoint {
+            Some(saved) if new_block.is_same_candidate(saved) => {
+                // The timestamp is hashed too, so it has to match the saved attempt.
+                new_block.timestamp = saved.timestamp;
+                new_block.nonce = saved.nonce;
+                println!("[INFO] Resuming Proof-of-Work from nonce {}...", saved.nonce);
+            }
+            Some(_) => println!("[INFO] The saved mining checkpoint is for a different block. Starting fresh."),
+            None => println!("[INFO] Starting Proof-of-Work for new block..."),
+        }
+        let stats = new_block.mine_with_checkpoints(on_checkpoint);
+
+        self.chain.push(new_block);
+        Ok(stats)
+    }
+
+    /// The unmined next block: a coinbase paying `miner_address`, then
+    /// `transactions`, which must all be final and funded.
+    fn assemble_block(
+        &self,
+        miner_address: PublicKey,
+        transactions: Vec<Transaction>,
+        difficulty: usize,
+    ) -> Result<Block> {
+        let height = self.chain.len() as u64;
+        let mut reward_tx = Transaction::new_coinbase(miner_address, block_reward(height));
+        if self.coinbase_maturity > 0 {
+            reward_tx.condition = Some(SpendCondition::Timelock {
+                height: height.saturating_add(self.coinbase_maturity),
+            });
+        }
+
+        if let Some(tx) = transactions.iter().find(|tx| !tx.is_final(height)) {
+            bail!(
+                "Transaction {} can't be mined before block #{}.",
+                tx.txid(),
+                tx.locktime.unwrap_or_default()
+            );
+        }
+
+        let mut transactions_for_block = transactions;
+        transactions_for_block.insert(0, reward_tx);
+
+        let mut ledger = Ledger::replay(&self.chain)?;
+        for tx in &transactions_for_block {
+            ledger.apply(tx, height)?;
+        }
 
         let previous_hash = self.chain.last().unwrap().hash.clone();
-        let mut new_block = Block::new(
-            self.chain.len() as u64,
-            transactions_for_block,
-            previous_hash,
-            self.difficulty,
-        );
+        let mut block = Block::new(height, transactions_for_block, previous_hash, difficulty);
+        block.version = block.version.max(self.required_block_version(height));
+        Ok(block)
+    }
+
+    /// The lowest version a block at `height` may have under `version_activation`.
+    pub fn required_block_version(&self, height: u64) -> u32 {
+        self.version_activation
+            .filter(|activation| height >= activation.height)
+            .map_or_else(legacy_version, |activation| activation.min_block_version)
+    }
 
+    /// Mines the block `mine_pending_transactions` would, but hands it back
+    /// instead of appending it: the chain and mempool are left untouched.
+    pub fn mine_candidate_block(&self, miner_address: PublicKey) -> Result<(Block, MiningStats)> {
+        let (transactions, _) = self.minable_transactions()?;
+        let difficulty = next_difficulty(&self.chain, self.difficulty);
+        let mut block = self.assemble_block(miner_address, transactions, difficulty)?;
         println!("[INFO] Starting Proof-of-Work for new block...");
-        new_block.mine();
+        let stats = block.mine();
+        Ok((block, stats))
+    }
 
-        self.chain.push(new_block);
-        self.mempool.clear();
+    /// Appends a block mined elsewhere (e.g. by `mine_candidate_block`) after
+    /// checking it extends the tip with valid proof-of-work at the expected
+    /// difficulty and passes the same checks as the rest of the chain. Its
+    /// transactions are dropped from the mempool.
+    pub fn append_block(&mut self, block: Block) -> Result<()> {
+        let tip = self.chain.last().unwrap();
+        if block.index != self.chain.len() as u64 || block.previous_hash != tip.hash {
+            bail!(
+                "That block is #{} on top of {}, but this chain's tip is #{} ({}).",
+                block.index,
+                short(&block.previous_hash),
+                tip.index,
+                short(&tip.hash)
+            );
+        }
+        let difficulty = next_difficulty(&self.chain, self.difficulty);
+        if block.difficulty != difficulty {
+            bail!(
+                "That block was mined at difficulty {}, but the next block needs {}.",
+                block.difficulty,
+                difficulty
+            );
+        }
+        if !block.verify_hash() || !block.has_valid_pow() {
+            bail!("That block's hash doesn't match its contents or doesn't meet its difficulty.");
+        }
+        let required = self.required_block_version(block.index);
+        if block.version < required {
+            bail!(
+                "That block is version {}, but blocks from #{} on must be at least version {}.",
+                block.version,
+                block.index,
+                required
+            );
+        }
+
+        if !block.has_unambiguous_transactions() {
+            bail!("That block lists the same transaction more than once.");
+        }
+
+        let included: Vec<String> = block.transactions.iter().map(Transaction::txid).collect();
+        self.chain.push(block);
+        if !self.is_valid_from(self.chain.len() - 1) {
+            self.chain.pop();
+            bail!(BlockchainError::ChainInvalid);
+        }
+        self.difficulty = difficulty;
+        self.mempool.retain(|tx| !included.contains(&tx.txid()));
         Ok(())
     }
 
-    pub fn get_balance(&self, address: &PublicKey) -> i64 {
+    /// Confirmed balance of `address`. Fails if it overflows along the way,
+    /// which only a chain with crafted amounts can do.
+    pub fn get_balance(&self, address: &PublicKey) -> Result<i64> {
         let mut balance = 0i64;
         for block in &self.chain {
             for tx in &block.transactions {
                 if tx.destination == *address {
-                    balance += tx.amount as i64;
+                    balance = checked_balance(balance, tx, true)?;
                 }
                 if let Some(source) = &tx.source {
                     if *source == *address {
-                        balance -= tx.amount as i64;
+                        balance = checked_balance(balance, tx, false)?;
                     }
                 }
             }
         }
-        balance
+        Ok(balance)
+    }
+
+    /// The nonce for `sender`'s next transaction: one more than the highest
+    /// it has used in the chain or mempool, or 0 if it hasn't used any.
+    pub fn next_nonce(&self, sender: &PublicKey) -> u64 {
+        self.chain
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .chain(&self.mempool)
+            .filter(|tx| tx.source.as_ref() == Some(sender))
+            .filter_map(|tx| tx.nonce)
+            .max()
+            .map_or(0, |nonce| nonce.saturating_add(1))
+    }
+
+    /// `get_balance` for every one of `addresses`, in a single pass over the chain.
+    pub fn get_balances(&self, addresses: &[PublicKey]) -> Result<HashMap<PublicKey, i64>> {
+        let mut balances: HashMap<PublicKey, i64> =
+            addresses.iter().map(|address| (address.clone(), 0)).collect();
+        for tx in self.chain.iter().flat_map(|block| &block.transactions) {
+            if let Some(balance) = balances.get_mut(&tx.destination) {
+                *balance = checked_balance(*balance, tx, true)?;
+            }
+            if let Some(balance) = tx.source.as_ref().and_then(|source| balances.get_mut(source)) {
+                *balance = checked_balance(*balance, tx, false)?;
+            }
+        }
+        Ok(balances)
+    }
+
+    /// Coins owned by `address` that are still held back by a spending
+    /// condition (e.g. a timelock) as of the next block.
+    pub fn locked_balance(&self, address: &PublicKey) -> u64 {
+        Ledger::replay(&self.chain)
+            .map(|ledger| ledger.locked(address, self.chain.len() as u64))
+            .unwrap_or(0)
+    }
+
+    /// Every confirmed transaction in chain order, with the height of its block.
+    pub fn iter_transactions(&self) -> impl Iterator<Item = (u64, &Transaction)> {
+        self.chain
+            .iter()
+            .flat_map(|block| block.transactions.iter().map(move |tx| (block.index, tx)))
+    }
+
+    /// Confirmed transactions that `address` sent or received, with the
+    /// height of the block each one is in.
+    pub fn transactions_involving<'a>(
+        &'a self,
+        address: &'a PublicKey,
+    ) -> impl Iterator<Item = (u64, &'a Transaction)> + 'a {
+        self.iter_transactions()
+            .filter(move |(_, tx)| tx.destination == *address || tx.source.as_ref() == Some(address))
+    }
+
+    /// Every address that has sent or received coins, in order of first
+    /// appearance, with its current balance. Computed in a single pass.
+    pub fn addresses(&self) -> Vec<AddressSummary> {
+        let mut positions: HashMap<PublicKey, usize> = HashMap::new();
+        let mut summaries: Vec<AddressSummary> = Vec::new();
+        for (height, tx) in self.iter_transactions() {
+            let sides = tx
+                .source
+                .iter()
+                .map(|source| (source, -signed_amount(tx)))
+                .chain([(&tx.destination, signed_amount(tx))]);
+            for (address, change) in sides {
+                let position = *positions.entry(address.clone()).or_insert_with(|| {
+                    summaries.push(AddressSummary {
+                        address: address.clone(),
+                        first_seen: height,
+                        balance: 0,
+                    });
+                    summaries.len() - 1
+                });
+                summaries[position].balance = summaries[position].balance.saturating_add(change);
+            }
+        }
+        summaries
+    }
+
+    /// Coins held times the number of blocks they've been held for, summed
+    /// over everything `address` currently owns. Spending uses up the oldest
+    /// coins first, and their age goes with them.
+    pub fn coin_age(&self, address: &PublicKey) -> u64 {
+        let tip = self.chain.len() as u64 - 1;
+        self.coin_lots(address)
+            .iter()
+            .fold(0u64, |total, (height, amount)| {
+                total.saturating_add(amount.saturating_mul(tip - height))
+            })
+    }
+
+    /// Confirmed balance of `address` not counting dust: received amounts
+    /// (what's left of them after spends) below `dust_threshold`, which would
+    /// cost more to spend than they're worth.
+    pub fn spendable_balance(&self, address: &PublicKey, dust_threshold: u64) -> u64 {
+        self.coin_lots(address)
+            .iter()
+            .map(|(_, amount)| *amount)
+            .filter(|amount| *amount >= dust_threshold)
+            .fold(0u64, u64::saturating_add)
+    }
+
+    /// What `address` currently owns as (height received, amount) lots,
+    /// oldest first. Spends use up the oldest lots first.
+    fn coin_lots(&self, address: &PublicKey) -> Vec<(u64, u64)> {
+        let mut lots: Vec<(u64, u64)> = Vec::new();
+        for (height, tx) in self.transactions_involving(address) {
+            if tx.source.as_ref() == Some(address) {
+                let mut to_spend = tx.amount;
+                while to_spend > 0 && !lots.is_empty() {
+                    let used = lots[0].1.min(to_spend);
+                    lots[0].1 -= used;
+                    to_spend -= used;
+                    if lots[0].1 == 0 {
+                        lots.remove(0);
+                    }
+                }
+            }
+            if tx.destination == *address {
+                lots.push((height, tx.amount));
+            }
+        }
+        lots
+    }
+
+    pub fn has_pending_from(&self, address: &PublicKey) -> bool {
+        self.mempool
+            .iter()
+            .any(|tx| tx.source.as_ref() == Some(address))
+    }
+
+    /// Removes and returns every pending transaction sent by `address`.
+    pub fn take_pending_from(&mut self, address: &PublicKey) -> Vec<Transaction> {
+        let (taken, kept) = std::mem::take(&mut self.mempool)
+            .into_iter()
+            .partition(|tx| tx.source.as_ref() == Some(address));
+        self.mempool = kept;
+        taken
+    }
+
+    /// Re-links and re-mines every block from height `from` to the tip so the
+    /// chain validates again. This rewrites history: every block hash from
+    /// `from` onwards changes. Returns the number of blocks re-mined.
+    pub fn repair_from(&mut self, from: usize) -> Result<usize> {
+        if from >= self.chain.len() {
+            bail!(
+                "There's no block #{}. The tip is #{}.",
+                from,
+                self.chain.len() - 1
+            );
+        }
+        for i in from..self.chain.len() {
+            if i > 0 {
+                self.chain[i].previous_hash = self.chain[i - 1].hash.clone();
+            }
+            let block = &mut self.chain[i];
+            if block.version >= 2 || !block.merkle_root.is_empty() {
+                block.merkle_root = block.compute_merkle_root();
+            }
+            block.nonce = 0;
+            block.mine();
+        }
+        self.validated_height = 0;
+        Ok(self.chain.len() - from)
+    }
+
+    /// Looks a transaction up by id, on-chain first and then in the mempool.
+    pub fn find_transaction(&self, txid: &str) -> Option<(TxLocation, &Transaction)> {
+        for block in &self.chain {
+            if let Some(tx) = block.transactions.iter().find(|tx| tx.txid() == txid) {
+                return Some((TxLocation::Block(block.index), tx));
+            }
+        }
+        self.mempool
+            .iter()
+            .find(|tx| tx.txid() == txid)
+            .map(|tx| (TxLocation::Mempool, tx))
+    }
+
+    /// Every transaction on-chain, then in the mempool, that matches `filter`.
+    pub fn search_transactions(&self, filter: &TxFilter) -> Vec<(TxLocation, &Transaction)> {
+        self.iter_transactions()
+            .map(|(height, tx)| (TxLocation::Block(height), tx))
+            .chain(self.mempool.iter().map(|tx| (TxLocation::Mempool, tx)))
+            .filter(|(_, tx)| filter.matches(tx))
+            .collect()
+    }
+
+    /// Number of blocks on top of (and including) the block at `height`.
+    pub fn confirmations(&self, height: u64) -> u64 {
+        (self.chain.len() as u64).saturating_sub(height)
+    }
+
+    /// Total amount `address` is already sending in transactions that haven't been mined yet.
+    pub fn pending_outgoing(&self, address: &PublicKey) -> u64 {
+        self.mempool
+            .iter()
+            .filter(|tx| tx.source.as_ref() == Some(address))
+            .map(|tx| tx.amount)
+            .fold(0u64, u64::saturating_add)
     }
 
     fn adjust_difficulty(&mut self) {
-        let latest_block = self.chain.last().unwrap();
-        if latest_block.index > 0 && latest_block.index % DIFFICULTY_ADJUSTMENT_INTERVAL == 0 {
-            let interval_start_block =
-                &self.chain[(latest_block.index - DIFFICULTY_ADJUSTMENT_INTERVAL) as usize];
-            let time_taken = latest_block.timestamp - interval_start_block.timestamp;
-            let expected_time = (DIFFICULTY_ADJUSTMENT_INTERVAL as i64) * TARGET_BLOCK_TIME_SECS;
-
-            if time_taken < expected_time / 2 {
-                self.difficulty += 1;
-                println!(
-                    "[INFO] Mining is getting too fast. Increasing difficulty to {}.",
-                    self.difficulty
-                );
-            } else if time_taken > expected_time * 2 && self.difficulty > 1 {
-                self.difficulty -= 1;
-                println!(
-                    "[INFO] Mining is too slow. Decreasing difficulty to {}.",
-                    self.difficulty
-                );
+        let next = next_difficulty(&self.chain, self.difficulty);
+        if next > self.difficulty {
+            println!("[INFO] Mining is getting too fast. Increasing difficulty to {}.", next);
+        } else if next < self.difficulty {
+            println!("[INFO] Mining is too slow. Decreasing difficulty to {}.", next);
+        }
+        self.difficulty = next;
+    }
+
+    /// Every completed difficulty-adjustment interval, oldest first.
+    pub fn difficulty_history(&self) -> Vec<RetargetPeriod> {
+        let interval = DIFFICULTY_ADJUSTMENT_INTERVAL as usize;
+        (interval..self.chain.len())
+            .step_by(interval)
+            .map(|end| {
+                let difficulty_before = self.chain[end].difficulty;
+                RetargetPeriod {
+                    start: (end - interval) as u64,
+                    end: end as u64,
+                    time_taken: self.chain[end].timestamp - self.chain[end - interval].timestamp,
+                    expected_time: DIFFICULTY_ADJUSTMENT_INTERVAL as i64 * TARGET_BLOCK_TIME_SECS,
+                    difficulty_before,
+                    difficulty_after: match self.chain.get(end + 1) {
+                        Some(next) => next.difficulty,
+                        None => next_difficulty(&self.chain[..=end], difficulty_before),
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// Replays the retargeting rule from genesis and returns the first block
+    /// whose recorded difficulty doesn't follow it, as (height, expected,
+    /// recorded). Catches chains forged with a lowered difficulty.
+    pub fn difficulty_mismatch(&self) -> Option<(u64, usize, usize)> {
+        let mut expected = self.chain[0].difficulty;
+        for (i, block) in self.chain.iter().enumerate().skip(1) {
+            expected = next_difficulty(&self.chain[..i], expected);
+            if block.difficulty != expected {
+                return Some((block.index, expected, block.difficulty));
             }
         }
+        None
+    }
+
+    /// Identifies the chain by its genesis block hash. Chains that were created
+    /// independently never share an id.
+    pub fn chain_id(&self) -> &str {
+        &self.chain[0].hash
+    }
+
+    /// Block interval, hash rate and throughput over the last `window` blocks
+    /// (fewer if the chain is shorter). `None` if there are no blocks after genesis.
+    pub fn rate(&self, window: usize) -> Option<ChainRate> {
+        let blocks = window.min(self.chain.len() - 1);
+        if blocks == 0 {
+            return None;
+        }
+        let recent = &self.chain[self.chain.len() - blocks..];
+        let before = &self.chain[self.chain.len() - blocks - 1];
+        Some(ChainRate {
+            blocks,
+            elapsed_secs: recent[blocks - 1].timestamp - before.timestamp,
+            work: recent
+                .iter()
+                .fold(0u128, |total, block| total.saturating_add(block.work())),
+            transactions: recent
+                .iter()
+                .flat_map(|block| &block.transactions)
+                .filter(|tx| tx.source.is_some())
+                .count(),
+        })
+    }
+
+    /// Sum of every block's proof-of-work, used for fork choice.
+    pub fn total_work(&self) -> u128 {
+        self.chain
+            .iter()
+            .fold(0u128, |total, block| total.saturating_add(block.work()))
+    }
+
+    /// Adopts `incoming` as the local chain if it is a valid version of the
+    /// same chain with more accumulated work. Transactions from local blocks that the new chain
+    /// dropped go back into the mempool if they still validate.
+    pub fn replace_chain(&mut self, incoming: Blockchain) -> Result<ReorgReport> {
+        self.replace_chain_within(incoming, MAX_REORG_DEPTH)
+    }
+
+    /// Like `replace_chain`, but refuses to give up more than `max_depth` of
+    /// the local blocks, however much work the incoming chain has. Deeply
+    /// confirmed blocks are treated as final.
+    pub fn replace_chain_within(&mut self, mut incoming: Blockchain, max_depth: u64) -> Result<ReorgReport> {
+        if incoming.network != self.network {
+            bail!(
+                "That chain belongs to the '{}' network, but yours is on '{}'.",
+                incoming.network,
+                self.network
+            );
+        }
+        if incoming.chain.is_empty() || incoming.chain_id() != self.chain_id() {
+            bail!("That chain has a different genesis block. It isn't the same chain as yours.");
+        }
+        // Judge the incoming blocks by our activation rule, not theirs.
+        incoming.version_activation = self.version_activation;
+        if !incoming.is_chain_valid() {
+            bail!(BlockchainError::ChainInvalid);
+        }
+        // Most accumulated proof-of-work wins, not most blocks: a long run of
+        // easy blocks mustn't beat fewer, harder ones.
+        if incoming.total_work() <= self.total_work() {
+            bail!(
+                "That chain has {} total work, which isn't more than yours ({}).",
+                incoming.total_work(),
+                self.total_work()
+            );
+        }
+
+        let fork_height = self
+            .chain
+            .iter()
+            .zip(&incoming.chain)
+            .take_while(|(ours, theirs)| ours.hash == theirs.hash)
+            .count();
+        let depth = (self.chain.len() - fork_height) as u64;
+        if depth > max_depth {
+            bail!(
+                "That chain forks off at block #{}, which would replace {} of your blocks. Reorganizations deeper than {} are refused.",
+                fork_height,
+                depth,
+                max_depth
+            );
+        }
+        let orphaned: Vec<Transaction> = self.chain[fork_height..]
+            .iter()
+            .flat_map(|block| block.transactions.iter().skip(1))
+            .cloned()
+            .collect();
+
+        self.chain = incoming.chain;
+        self.difficulty = incoming.difficulty;
+        self.validated_height = 0;
+
+        let confirmed: HashSet<String> = self
+            .chain
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .map(Transaction::txid)
+            .collect();
+        self.mempool.retain(|tx| !confirmed.contains(&tx.txid()));
+
+        let mut report = ReorgReport {
+            fork_height: fork_height as u64,
+            ..Default::default()
+        };
+        for tx in orphaned {
+            let txid = tx.txid();
+            if confirmed.contains(&txid) || self.mempool.iter().any(|p| p.txid() == txid) {
+                continue;
+            }
+            match self.add_transaction(tx) {
+                Ok(()) => report.reinjected.push(txid),
+                Err(err) => report.dropped.push((txid, err.to_string())),
+            }
+        }
+        Ok(report)
     }
 
     pub fn is_chain_valid(&self) -> bool {
-        for i in 1..self.chain.len() {
-            let current_block = &self.chain[i];
-            let previous_block = &self.chain[i - 1];
-            if current_block.previous_hash != previous_block.hash {
+        self.is_valid_from(1)
+    }
+
+    /// Validates the chain, skipping the per-transaction checks for blocks
+    /// well below `validated_height` unless `full` is set. Returns the first
+    /// height whose transactions were re-checked, or `None` if the chain is
+    /// invalid. Every block's link, hash and proof-of-work are still checked,
+    /// so an edit below the watermark is caught; balances are always
+    /// replayed from genesis, since they depend on every block.
+    pub fn validate(&mut self, full: bool) -> Option<u64> {
+        let start = if full {
+            1
+        } else {
+            self.validated_height.saturating_sub(VALIDATION_OVERLAP).max(1)
+        };
+        if self.is_valid_from(start as usize) {
+            self.validated_height = self.chain.len() as u64 - 1;
+            Some(start)
+        } else {
+            self.validated_height = 0;
+            None
+        }
+    }
+
+    /// Checks every block's link, hash and proof-of-work, and the
+    /// transactions of the blocks from `start` on.
+    fn is_valid_from(&self, start: usize) -> bool {
+        // The genesis block is the one block nothing links to, so check its
+        // own hash too. The rest are pinned by the next block's link.
+        if !(self.chain[0].verify_hash() && self.chain[0].has_valid_pow()) {
+            return false;
+        }
+        for pair in self.chain.windows(2) {
+            let (previous_block, current_block) = (&pair[0], &pair[1]);
+            if current_block.previous_hash != previous_block.hash
+                || !current_block.verify_hash()
+                || !current_block.has_valid_pow()
+            {
+                return false;
+            }
+        }
+        // Transfers already confirmed, so version 2+ blocks can't replay them.
+        // Coinbases repeat whenever a miner earns the same reward, so they're
+        // exempt, as are the identical payments some version 1 blocks hold.
+        let mut confirmed: HashSet<String> = self.chain[..start.max(1)]
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .filter(|tx| tx.source.is_some())
+            .map(Transaction::txid)
+            .collect();
+        for current_block in &self.chain[start..] {
+            if current_block.version < self.required_block_version(current_block.index)
+                || !current_block.has_unambiguous_transactions()
+            {
+                return false;
+            }
+            // Exactly one coinbase, paying the block's reward, and it comes first.
+            let mut sources = current_block.transactions.iter().map(|tx| tx.source.is_some());
+            if sources.next() != Some(false) || sources.any(|has_source| !has_source) {
+                return false;
+            }
+            if current_block.transactions[0].amount != block_reward(current_block.index) {
                 return false;
             }
             for tx in &current_block.transactions {
-                if !tx.is_valid() {
+                let replayed = tx.source.is_some() && !confirmed.insert(tx.txid());
+                // Version 3 closes the gaps the txid check leaves: transfers
+                // without a nonce, and copies with a negated signature.
+                let unprotected = tx.source.is_some() && (tx.nonce.is_none() || !tx.has_low_s());
+                if (replayed && current_block.version >= 2)
+                    || (unprotected && current_block.version >= 3)
+                    || !tx.is_valid()
+                    || tx.uses_degenerate_key()
+                    || !tx.is_final(current_block.index)
+                    || tx.network.as_ref().is_some_and(|network| *network != self.network)
+                {
                     return false;
                 }
             }
         }
-        true
+        Ledger::replay(&self.chain).is_ok()
+    }
+}
+
+/// Difficulty for the block after `chain`'s tip, given the difficulty the
+/// tip was mined at. Every `DIFFICULTY_ADJUSTMENT_INTERVAL` blocks it steps
+/// up if that interval took under half the target time, and down if it took
+/// over twice as long.
+fn next_difficulty(chain: &[Block], current: usize) -> usize {
+    let latest_block = chain.last().unwrap();
+    if latest_block.index > 0 && latest_block.index.is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL) {
+        let interval_start_block = &chain[(latest_block.index - DIFFICULTY_ADJUSTMENT_INTERVAL) as usize];
+        let time_taken = latest_block.timestamp - interval_start_block.timestamp;
+        let expected_time = (DIFFICULTY_ADJUSTMENT_INTERVAL as i64) * TARGET_BLOCK_TIME_SECS;
+
+        if time_taken < expected_time / 2 {
+            return current + 1;
+        } else if time_taken > expected_time * 2 && current > 1 {
+            return current - 1;
+        }
+    }
+    current
+}
+
+/// What the genesis block of `network` points back to, so that every
+/// network has a different genesis block.
+fn genesis_previous_hash(network: &str) -> String {
+    if network == MAIN_NETWORK {
+        "0".to_string()
+    } else {
+        hex::encode(Sha256::digest(format!("network:{}", network)))
+    }
+}
+
+/// `tx`'s amount as a balance change, clamped to `i64::MAX`. Validation
+/// rejects amounts that big, so this only matters for unvalidated chains.
+fn signed_amount(tx: &Transaction) -> i64 {
+    i64::try_from(tx.amount).unwrap_or(i64::MAX)
+}
+
+/// `balance` after `tx`'s amount comes in (`incoming`) or goes out. Fails
+/// instead of saturating, since a balance that can't be represented means
+/// the chain holds crafted amounts.
+fn checked_balance(balance: i64, tx: &Transaction, incoming: bool) -> Result<i64> {
+    i64::try_from(tx.amount)
+        .ok()
+        .and_then(|amount| if incoming { balance.checked_add(amount) } else { balance.checked_sub(amount) })
+        .context(BlockchainError::ChainInvalid)
+        .with_context(|| format!("Transaction {} overflows a balance. The chain holds crafted amounts.", tx.txid()))
+}
+
+fn check_transaction_size(tx: &Transaction) -> Result<()> {
+    let size = serde_json::to_vec(tx)?.len();
+    if size > MAX_TRANSACTION_BYTES {
+        bail!(
+            "Transaction is {} bytes, more than the limit of {}.",
+            size,
+            MAX_TRANSACTION_BYTES
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::BLOCK_VERSION;
+    use crate::wallet::Wallet;
+    use crate::testkit::{funded_wallet, malleated, mined_chain, seeded_wallet, signed_tx};
+
+    fn activate(chain: &mut Blockchain, height: u64, min_block_version: u32) {
+        chain.version_activation = Some(VersionActivation {
+            height,
+            min_block_version,
+        });
+    }
+
+    #[test]
+    fn blocks_mined_after_activation_get_the_required_version() {
+        let (mut chain, miner) = mined_chain(1, 0);
+        activate(&mut chain, 2, 4);
+        chain.mine_pending_transactions(PublicKey(miner.public_key)).unwrap();
+        assert_eq!((chain.chain[1].version, chain.chain[2].version), (BLOCK_VERSION, 4));
+        assert!(chain.is_chain_valid());
+    }
+
+    #[test]
+    fn activation_rejects_older_blocks_above_its_height() {
+        let (mut chain, _) = mined_chain(2, 0);
+        activate(&mut chain, 2, 4);
+        assert!(!chain.is_chain_valid());
+        activate(&mut chain, 3, 4);
+        assert!(chain.is_chain_valid());
+    }
+
+    #[test]
+    fn blocks_repeating_a_transaction_are_refused() {
+        let (mut chain, payer) = funded_wallet(50);
+        for amount in [10, 20] {
+            chain.add_transaction(signed_tx(&chain, &payer, &seeded_wallet(1), amount)).unwrap();
+        }
+        let (mut block, _) = chain.mine_candidate_block(PublicKey(payer.public_key)).unwrap();
+        let last = block.transactions[2].clone();
+        block.transactions.push(last);
+        assert!(block.verify_hash() && block.has_valid_pow());
+        assert!(chain.append_block(block).is_err());
+        assert_eq!(chain.chain.len(), 2);
+    }
+
+    /// Mines exactly `transactions` on top of `chain` with a valid hash and
+    /// proof-of-work, but without any of `append_block`'s checks.
+    fn mine_raw(chain: &Blockchain, transactions: Vec<Transaction>) -> Block {
+        let height = chain.chain.len() as u64;
+        let mut block = Block::new(height, transactions, chain.chain.last().unwrap().hash.clone(), chain.difficulty);
+        block.mine();
+        block
+    }
+
+    /// `mine_raw` with the correct coinbase for `miner` in front.
+    fn forge_block(chain: &Blockchain, miner: &Wallet, transactions: Vec<Transaction>) -> Block {
+        let reward = block_reward(chain.chain.len() as u64);
+        let mut all = vec![Transaction::new_coinbase(PublicKey(miner.public_key), reward)];
+        all.extend(transactions);
+        mine_raw(chain, all)
+    }
+
+    #[test]
+    fn each_block_pays_the_reward_to_its_miner() {
+        let (chain, miner) = mined_chain(3, 0);
+        for block in &chain.chain[1..] {
+            let coinbase = &block.transactions[0];
+            assert_eq!((coinbase.source.as_ref(), coinbase.amount), (None, block_reward(block.index)));
+            assert_eq!(coinbase.destination, PublicKey(miner.public_key));
+        }
+    }
+
+    #[test]
+    fn deterministic_mining_gives_the_same_block_every_time() {
+        let (mut chain, payer) = funded_wallet(50);
+        chain.add_transaction(signed_tx(&chain, &payer, &seeded_wallet(1), 10)).unwrap();
+        let mut again = chain.clone();
+        for _ in 0..2 {
+            chain.mine_pending_transactions_deterministic(PublicKey(payer.public_key)).unwrap();
+            again.mine_pending_transactions_deterministic(PublicKey(payer.public_key)).unwrap();
+            assert_eq!(chain.chain.last().unwrap().hash, again.chain.last().unwrap().hash);
+        }
+        assert_eq!(chain.chain[2].transactions.len(), 2);
+        assert!(chain.is_chain_valid());
+    }
+
+    /// Re-hashes `block` with the first nonce that misses its difficulty,
+    /// so the hash matches the contents but there's no proof-of-work.
+    fn unmine(block: &mut Block) {
+        block.nonce = 0;
+        block.hash = block.calculate_hash();
+        while block.has_valid_pow() {
+            block.nonce += 1;
+            block.hash = block.calculate_hash();
+        }
+    }
+
+    #[test]
+    fn edited_blocks_fail_validation() {
+        let (mut chain, payer) = funded_wallet(50);
+        chain.add_transaction(signed_tx(&chain, &payer, &seeded_wallet(1), 10)).unwrap();
+        chain.mine_pending_transactions(PublicKey(payer.public_key)).unwrap();
+        chain.mine_pending_transactions(PublicKey(payer.public_key)).unwrap();
+        assert!(chain.is_chain_valid());
+
+        let tampered = crate::attack::tamper_amount(&chain).unwrap();
+        assert!(!tampered.chain.is_chain_valid());
+
+        // Re-signing can't help once the block is mined: the hash gives it away.
+        let mut rehashed = chain.clone();
+        rehashed.chain[2].transactions[1] = signed_tx(&chain, &payer, &seeded_wallet(1), 40);
+        assert!(!rehashed.chain[2].verify_hash());
+        assert!(!rehashed.is_chain_valid());
+    }
+
+    #[test]
+    fn blocks_without_proof_of_work_are_refused() {
+        let (mut chain, miner) = mined_chain(1, 0);
+        let mut block = forge_block(&chain, &miner, vec![]);
+        unmine(&mut block);
+        assert!(block.verify_hash());
+        assert!(chain.append_block(block.clone()).is_err());
+        chain.chain.push(block);
+        assert!(!chain.is_chain_valid());
+    }
+
+    #[test]
+    fn genesis_needs_proof_of_work_too() {
+        let (mut chain, _) = mined_chain(1, 0);
+        unmine(&mut chain.chain[0]);
+        chain.chain[1].previous_hash = chain.chain[0].hash.clone();
+        chain.chain[1].mine();
+        assert!(!chain.is_chain_valid());
+    }
+
+    #[test]
+    fn blocks_with_a_bad_coinbase_are_refused() {
+        let (mut chain, miner) = funded_wallet(50);
+        let me = PublicKey(miner.public_key);
+        let reward = block_reward(chain.chain.len() as u64);
+        let payment = signed_tx(&chain, &miner, &seeded_wallet(1), 10);
+        let coinbase = |amount| Transaction::new_coinbase(me.clone(), amount);
+        for transactions in [
+            vec![],
+            vec![coinbase(reward + 1)],
+            vec![coinbase(reward - 1)],
+            vec![payment.clone()],
+            vec![payment, coinbase(reward)],
+            vec![coinbase(reward), coinbase(reward)],
+        ] {
+            let block = mine_raw(&chain, transactions);
+            assert!(chain.append_block(block).is_err());
+        }
+        assert!(chain.append_block(forge_block(&chain, &miner, vec![])).is_ok());
+    }
+
+    #[test]
+    fn overspending_is_refused() {
+        let (mut chain, payer) = funded_wallet(50);
+        let balance = chain.get_balance(&PublicKey(payer.public_key)).unwrap();
+        let err = chain
+            .add_transaction(signed_tx(&chain, &payer, &seeded_wallet(1), balance as u64 + 1))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&BlockchainError::InsufficientFunds {
+                available: balance,
+                requested: balance as u64 + 1,
+            })
+        );
+        assert!(chain.mempool.is_empty());
+    }
+
+    #[test]
+    fn pending_spends_count_against_the_balance() {
+        let (mut chain, payer) = funded_wallet(50);
+        let balance = chain.get_balance(&PublicKey(payer.public_key)).unwrap();
+        chain.add_transaction(signed_tx(&chain, &payer, &seeded_wallet(1), 60)).unwrap();
+        let err = chain
+            .add_transaction(signed_tx(&chain, &payer, &seeded_wallet(2), 50))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&BlockchainError::InsufficientFunds {
+                available: balance - 60,
+                requested: 50,
+            })
+        );
+        assert_eq!(chain.mempool.len(), 1);
+    }
+
+    #[test]
+    fn premined_chains_verify_and_edited_premines_dont() {
+        let (alice, bob) = (PublicKey(seeded_wallet(1).public_key), PublicKey(seeded_wallet(2).public_key));
+        let mut chain = Blockchain::new_with_premine("test", &[(alice.clone(), 500), (bob.clone(), 20)]).unwrap();
+        assert!(chain.is_chain_valid());
+        assert_eq!(chain.get_balance(&alice).unwrap(), 500);
+        chain.add_transaction(signed_tx(&chain, &seeded_wallet(1), &seeded_wallet(2), 300)).unwrap();
+        chain.mine_pending_transactions(alice.clone()).unwrap();
+        assert!(chain.is_chain_valid());
+        assert_eq!(chain.get_balance(&bob).unwrap(), 320);
+
+        let mut tampered = chain.clone();
+        tampered.chain[0].transactions[1].amount = 20_000;
+        assert!(!tampered.is_chain_valid());
+        // Re-mining genesis doesn't help: the next block no longer links to it.
+        tampered.chain[0].mine();
+        assert!(!tampered.is_chain_valid());
+    }
+
+    #[test]
+    fn overflowing_balances_are_errors_not_clamped() {
+        let whale = seeded_wallet(1);
+        let address = PublicKey(whale.public_key);
+        let mut chain = Blockchain::new_with_premine("main", &[(address.clone(), i64::MAX as u64)]).unwrap();
+        assert_eq!(chain.get_balance(&address).unwrap(), i64::MAX);
+
+        // Slipped in without append_block, which would refuse it.
+        chain.chain.push(forge_block(&chain, &whale, vec![]));
+        assert!(!chain.is_chain_valid());
+        let err = chain.get_balance(&address).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&BlockchainError::ChainInvalid));
+        assert!(err.to_string().contains("overflows a balance"), "{}", err);
+        assert!(chain.get_balances(std::slice::from_ref(&address)).is_err());
+
+        let (mut chain, miner) = mined_chain(0, 0);
+        chain.chain.push(mine_raw(&chain, vec![Transaction::new_coinbase(PublicKey(miner.public_key), u64::MAX)]));
+        assert!(chain.get_balance(&PublicKey(miner.public_key)).is_err());
+    }
+
+    #[test]
+    fn recoverable_transactions_with_a_wrong_recovery_id_or_source_are_refused() {
+        let (mut chain, payer) = funded_wallet(50);
+        let tx = Transaction::builder(PublicKey(seeded_wallet(1).public_key), 10)
+            .recoverable()
+            .network(&chain.network)
+            .nonce(0)
+            .sign(&payer);
+
+        let mut wrong_id = tx.clone();
+        wrong_id.recovery_id = tx.recovery_id.map(|recovery_id| recovery_id ^ 1);
+        let mut swapped = tx.clone();
+        swapped.source = Some(PublicKey(seeded_wallet(2).public_key));
+        for forged in [wrong_id, swapped] {
+            let err = chain.add_transaction(forged).unwrap_err();
+            assert!(err.to_string().contains("bad signature"), "{}", err);
+        }
+        assert!(chain.mempool.is_empty());
+        chain.add_transaction(tx).unwrap();
+    }
+
+    #[test]
+    fn degenerate_keys_are_refused() {
+        // Private keys 1 and n-1: the generator and its negation.
+        let degenerate = [
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632550",
+        ]
+        .map(|hex_key| {
+            let bytes = hex::decode(hex_key).unwrap();
+            Wallet::from_signing_key(p256::ecdsa::SigningKey::from_slice(&bytes).unwrap())
+        });
+        let (mut chain, payer) = funded_wallet(50);
+        for wallet in &degenerate {
+            assert!(PublicKey(wallet.public_key).is_degenerate());
+            let from_degenerate = Transaction::new(wallet, PublicKey(payer.public_key), 1, 0);
+            let to_degenerate = signed_tx(&chain, &payer, wallet, 1);
+            for tx in [from_degenerate, to_degenerate] {
+                let err = chain.add_transaction(tx).unwrap_err();
+                assert!(err.to_string().contains("degenerate key"), "{}", err);
+            }
+        }
+        assert!(chain.mempool.is_empty());
+        // The identity point can't even be decoded into a key.
+        assert!(PublicKey::from_hex("00").is_err());
+    }
+
+    #[test]
+    fn oversized_transactions_are_refused() {
+        let (mut chain, payer) = funded_wallet(50);
+        let tx = Transaction::builder(PublicKey(seeded_wallet(1).public_key), 10)
+            .network(&"x".repeat(MAX_TRANSACTION_BYTES))
+            .nonce(0)
+            .sign(&payer);
+        let err = chain.add_transaction(tx.clone()).unwrap_err();
+        assert!(err.to_string().contains("more than the limit"), "{}", err);
+        assert!(chain.mempool.is_empty());
+
+        chain.mempool.push(tx);
+        assert!(chain.check_limits().is_err());
+    }
+
+    #[test]
+    fn blocks_over_the_transaction_cap_are_refused() {
+        let (mut chain, miner) = mined_chain(1, 0);
+        assert!(chain.check_limits().is_ok());
+        let coinbase = chain.chain[1].transactions[0].clone();
+        chain.chain[1].transactions = vec![coinbase; MAX_BLOCK_TRANSACTIONS + 1];
+        let err = chain.check_limits().unwrap_err();
+        assert!(err.to_string().contains("more than the limit"), "{}", err);
+
+        // Selecting more than a block can hold is refused before mining.
+        let (mut chain, _) = mined_chain(1, 0);
+        let tx = signed_tx(&chain, &miner, &seeded_wallet(1), 10);
+        let txids = vec![tx.txid(); MAX_BLOCK_TRANSACTIONS];
+        chain.add_transaction(tx).unwrap();
+        let err = chain.mine_selected_transactions(PublicKey(miner.public_key), &txids).unwrap_err();
+        assert!(err.to_string().contains("at most"), "{}", err);
+        assert_eq!(chain.chain.len(), 2);
+    }
+
+    #[test]
+    fn a_signed_transaction_is_accepted_once() {
+        let (mut chain, payer) = funded_wallet(50);
+        let tx = signed_tx(&chain, &payer, &seeded_wallet(1), 10);
+        chain.add_transaction(tx.clone()).unwrap();
+        assert!(chain.add_transaction(tx.clone()).is_err());
+        chain.mine_pending_transactions(PublicKey(payer.public_key)).unwrap();
+        assert!(chain.add_transaction(tx).is_err());
+        assert_eq!(chain.get_balance(&PublicKey(seeded_wallet(1).public_key)).unwrap(), 10);
+    }
+
+    #[test]
+    fn replaying_a_transaction_in_a_later_block_is_refused() {
+        let (mut chain, payer) = funded_wallet(50);
+        let tx = signed_tx(&chain, &payer, &seeded_wallet(1), 10);
+        chain.add_transaction(tx.clone()).unwrap();
+        chain.mine_pending_transactions(PublicKey(payer.public_key)).unwrap();
+
+        let replay = forge_block(&chain, &payer, vec![tx]);
+        assert!(chain.append_block(replay.clone()).is_err());
+        chain.chain.push(replay);
+        assert!(!chain.is_chain_valid());
+    }
+
+    #[test]
+    fn transfers_without_a_nonce_are_refused() {
+        let (mut chain, payer) = funded_wallet(50);
+        let legacy = Transaction::builder(PublicKey(seeded_wallet(1).public_key), 10).sign(&payer);
+        let err = chain.add_transaction(legacy.clone()).unwrap_err();
+        assert!(err.to_string().contains("no nonce"));
+
+        // Version 2 blocks still accept them, for chains signed before nonces.
+        let mut old = forge_block(&chain, &payer, vec![legacy.clone()]);
+        old.version = 2;
+        old.mine();
+        let mut with_old = chain.clone();
+        with_old.chain.push(old);
+        assert!(with_old.is_chain_valid());
+
+        let block = forge_block(&chain, &payer, vec![legacy]);
+        assert_eq!(block.version, BLOCK_VERSION);
+        assert!(chain.append_block(block).is_err());
+    }
+
+    #[test]
+    fn malleated_copies_are_refused() {
+        let (mut chain, payer) = funded_wallet(50);
+        let tx = signed_tx(&chain, &payer, &seeded_wallet(1), 10);
+        let copy = malleated(&tx);
+        assert!(tx.has_low_s() && copy.is_valid() && !copy.has_low_s());
+        assert_ne!(copy.txid(), tx.txid());
+
+        assert!(chain.add_transaction(copy.clone()).is_err());
+        chain.add_transaction(tx).unwrap();
+        assert!(chain.add_transaction(copy.clone()).is_err());
+        chain.mine_pending_transactions(PublicKey(payer.public_key)).unwrap();
+        assert!(chain.add_transaction(copy.clone()).is_err());
+
+        let replay = forge_block(&chain, &payer, vec![copy]);
+        assert!(chain.append_block(replay.clone()).is_err());
+        chain.chain.push(replay);
+        assert!(!chain.is_chain_valid());
+        assert_eq!(chain.get_balance(&PublicKey(seeded_wallet(1).public_key)).unwrap(), 20);
+    }
+
+    #[test]
+    fn pending_and_confirmed_duplicates_are_bad_input() {
+        let (mut chain, payer) = funded_wallet(50);
+        let tx = signed_tx(&chain, &payer, &seeded_wallet(1), 10);
+        chain.add_transaction(tx.clone()).unwrap();
+        let pending = chain.add_transaction(tx.clone()).unwrap_err();
+        assert!(matches!(pending.downcast_ref(), Some(BlockchainError::InvalidInput(_))));
+        assert!(pending.to_string().contains("already in the mempool"));
+        assert_eq!(chain.mempool.len(), 1);
+
+        chain.mine_pending_transactions(PublicKey(payer.public_key)).unwrap();
+        let confirmed = chain.add_transaction(tx.clone()).unwrap_err();
+        assert!(matches!(confirmed.downcast_ref(), Some(BlockchainError::InvalidInput(_))));
+        assert!(confirmed.to_string().contains("already confirmed in block #2"));
+        assert!(chain.mempool.is_empty());
+    }
+
+    #[test]
+    fn imports_skip_duplicates_of_known_transactions() {
+        let (mut chain, payer) = funded_wallet(50);
+        let first = signed_tx(&chain, &payer, &seeded_wallet(1), 10);
+        chain.add_transaction(first.clone()).unwrap();
+        chain.mine_pending_transactions(PublicKey(payer.public_key)).unwrap();
+        let second = signed_tx(&chain, &payer, &seeded_wallet(1), 20);
+
+        let report = chain.import_transactions(vec![first, second.clone(), second.clone()]);
+        assert_eq!(report.accepted, vec![second.txid()]);
+        assert_eq!(report.rejected.len(), 2);
+        assert_eq!(chain.mempool, vec![second]);
+    }
+
+    #[test]
+    fn nonces_are_assigned_in_order() {
+        let (mut chain, payer) = funded_wallet(50);
+        let payee = PublicKey(seeded_wallet(1).public_key);
+        let sender = PublicKey(payer.public_key);
+        assert_eq!(chain.next_nonce(&sender), 0);
+        chain.add_transaction(Transaction::new(&payer, payee.clone(), 5, 0)).unwrap();
+        assert_eq!(chain.next_nonce(&sender), 1);
+        assert!(chain.add_transaction(Transaction::new(&payer, payee.clone(), 6, 0)).is_err());
+        chain.mine_pending_transactions(sender.clone()).unwrap();
+        assert_eq!(chain.next_nonce(&sender), 1);
+        assert!(chain.add_transaction(Transaction::new(&payer, payee, 7, 0)).is_err());
+    }
+
+    /// `base` extended by `blocks` blocks mined by the wallet from `seed`,
+    /// so branches from different seeds fork right after `base`.
+    fn branch(base: &Blockchain, blocks: usize, seed: u64) -> Blockchain {
+        let mut chain = base.clone();
+        for _ in 0..blocks {
+            chain.mine_pending_transactions(PublicKey(seeded_wallet(seed).public_key)).unwrap();
+        }
+        chain
+    }
+
+    #[test]
+    fn reorgs_deeper_than_the_limit_are_refused() {
+        let (base, _) = mined_chain(1, 0);
+        let mut local = branch(&base, 3, 1);
+        let incoming = branch(&base, 4, 2);
+        let tip = local.chain.last().unwrap().hash.clone();
+
+        let err = local.replace_chain_within(incoming.clone(), 2).unwrap_err();
+        assert!(err.to_string().contains("would replace 3 of your blocks"));
+        assert_eq!(local.chain.last().unwrap().hash, tip);
+
+        let report = local.replace_chain_within(incoming, 3).unwrap();
+        assert_eq!(report.fork_height, 2);
+        assert_eq!(local.chain.len(), 6);
+    }
+
+    /// Like `branch`, with the new blocks mined at `difficulty`.
+    fn branch_at(base: &Blockchain, blocks: usize, seed: u64, difficulty: usize) -> Blockchain {
+        let mut chain = base.clone();
+        chain.difficulty = difficulty;
+        branch(&chain, blocks, seed)
+    }
+
+    #[test]
+    fn fork_choice_follows_total_work_not_length() {
+        let (base, _) = mined_chain(1, 0);
+        let easy = base.difficulty;
+        let mut local = branch_at(&base, 1, 1, easy + 1);
+
+        let longer = branch_at(&base, 3, 2, easy);
+        assert!(longer.chain.len() > local.chain.len() && longer.total_work() < local.total_work());
+        assert!(local.replace_chain(longer).is_err());
+
+        let same_work = branch_at(&base, 1, 3, easy + 1);
+        assert_eq!(same_work.total_work(), local.total_work());
+        assert!(local.replace_chain(same_work).is_err());
+
+        let heavier = branch_at(&base, 2, 4, easy + 1);
+        let tip = heavier.chain.last().unwrap().hash.clone();
+        local.replace_chain(heavier).unwrap();
+        assert_eq!(local.chain.last().unwrap().hash, tip);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn block_work_grows_sixteenfold_per_difficulty() {
+        let (chain, _) = mined_chain(2, 0);
+        let work: u128 = chain.chain.iter().map(|block| 16u128.pow(block.difficulty as u32)).sum();
+        assert_eq!(chain.total_work(), work);
+    }
+
+    #[test]
+    fn orphaned_transactions_return_to_the_mempool() {
+        let (base, payer) = funded_wallet(50);
+        let mut local = base.clone();
+        let kept = signed_tx(&local, &payer, &seeded_wallet(1), 10);
+        local.add_transaction(kept.clone()).unwrap();
+        local.mine_pending_transactions(PublicKey(seeded_wallet(1).public_key)).unwrap();
+
+        let report = local.replace_chain(branch(&base, 2, 2)).unwrap();
+        assert_eq!(report.reinjected, vec![kept.txid()]);
+        assert!(report.dropped.is_empty());
+        assert_eq!(local.mempool, vec![kept]);
+    }
+
+    #[test]
+    fn orphaned_transactions_already_on_the_new_chain_are_not_repeated() {
+        let (base, payer) = funded_wallet(50);
+        let tx = signed_tx(&base, &payer, &seeded_wallet(1), 10);
+        let mut local = base.clone();
+        local.add_transaction(tx.clone()).unwrap();
+        local.mine_pending_transactions(PublicKey(seeded_wallet(1).public_key)).unwrap();
+        let mut incoming = base.clone();
+        incoming.add_transaction(tx).unwrap();
+        let incoming = branch(&incoming, 2, 2);
+
+        let report = local.replace_chain(incoming).unwrap();
+        assert!(report.reinjected.is_empty() && report.dropped.is_empty());
+        assert!(local.mempool.is_empty());
+    }
+
+    #[test]
+    fn orphaned_transactions_that_lost_their_funding_are_dropped() {
+        let (base, _) = mined_chain(1, 0);
+        let miner = seeded_wallet(1);
+        let mut local = branch(&base, 1, 1);
+        let spend = signed_tx(&local, &miner, &seeded_wallet(3), 60);
+        local.add_transaction(spend.clone()).unwrap();
+        local.mine_pending_transactions(PublicKey(miner.public_key)).unwrap();
+
+        let report = local.replace_chain(branch(&base, 3, 2)).unwrap();
+        assert!(report.reinjected.is_empty());
+        assert_eq!(report.dropped.len(), 1);
+        assert_eq!(report.dropped[0].0, spend.txid());
+        assert!(local.mempool.is_empty());
+    }
+
+    #[test]
+    fn activation_cant_be_met_by_editing_versions() {
+        let (mut chain, _) = mined_chain(2, 0);
+        activate(&mut chain, 1, 4);
+        let mut json = serde_json::to_value(&chain).unwrap();
+        for block in json["chain"].as_array_mut().unwrap() {
+            block["version"] = 4.into();
+        }
+        let edited: Blockchain = serde_json::from_value(json).unwrap();
+        assert!(edited.chain.iter().skip(1).all(|block| block.version == 4));
+        assert!(!edited.is_chain_valid());
+    }
+
+    #[test]
+    fn validate_rechecks_new_blocks_unless_full() {
+        let (mut chain, miner) = mined_chain(10, 0);
+        assert_eq!(chain.validate(false), Some(1));
+        assert_eq!(chain.validated_height, 10);
+        for _ in 0..3 {
+            chain.mine_pending_transactions(PublicKey(miner.public_key)).unwrap();
+        }
+        assert_eq!(chain.validate(false), Some(10 - VALIDATION_OVERLAP));
+        assert_eq!(chain.validated_height, 13);
+        assert_eq!(chain.validate(true), Some(1));
+    }
+
+    #[test]
+    fn validate_catches_edits_below_the_watermark() {
+        let (mut chain, _) = mined_chain(10, 0);
+        chain.validate(false).unwrap();
+        chain.chain[2].transactions[0].destination = PublicKey(seeded_wallet(1).public_key);
+        assert_eq!(chain.validate(false), None);
+        assert_eq!(chain.validated_height, 0);
+    }
+
+    #[test]
+    fn timelocked_coins_cant_be_spent_before_maturity() {
+        let (mut chain, payer) = funded_wallet(50);
+        let owner = seeded_wallet(1);
+        let unlock = chain.chain.len() as u64 + 2;
+        let locked = Transaction::builder(PublicKey(owner.public_key), 30)
+            .condition(SpendCondition::Timelock { height: unlock })
+            .nonce(chain.next_nonce(&PublicKey(payer.public_key)))
+            .sign(&payer);
+        chain.add_transaction(locked).unwrap();
+        chain.mine_pending_transactions(PublicKey(payer.public_key)).unwrap();
+
+        let err = chain.add_transaction(signed_tx(&chain, &owner, &seeded_wallet(2), 10)).unwrap_err();
+        assert!(err.to_string().contains("still locked at block #"), "{}", err);
+        chain.mine_pending_transactions(PublicKey(payer.public_key)).unwrap();
+        assert_eq!(chain.chain.len() as u64, unlock);
+        chain.add_transaction(signed_tx(&chain, &owner, &seeded_wallet(2), 10)).unwrap();
+    }
+
+    #[test]
+    fn coinbase_maturity_locks_mining_rewards() {
+        let (mut chain, _) = mined_chain(0, 0);
+        let miner = seeded_wallet(1);
+        chain.coinbase_maturity = 2;
+        chain.mine_pending_transactions(PublicKey(miner.public_key)).unwrap();
+        assert_eq!(chain.chain[1].transactions[0].condition, Some(SpendCondition::Timelock { height: 3 }));
+        assert!(chain.add_transaction(signed_tx(&chain, &miner, &seeded_wallet(2), 1)).is_err());
+
+        chain.mine_pending_transactions(PublicKey(miner.public_key)).unwrap();
+        let reward = block_reward(1);
+        assert_eq!(chain.locked_balance(&PublicKey(miner.public_key)), block_reward(2));
+        assert!(chain.add_transaction(signed_tx(&chain, &miner, &seeded_wallet(2), reward + 1)).is_err());
+        chain.add_transaction(signed_tx(&chain, &miner, &seeded_wallet(2), reward)).unwrap();
+        chain.mine_pending_transactions(PublicKey(miner.public_key)).unwrap();
+        assert!(chain.is_chain_valid());
+    }
+}
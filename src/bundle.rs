@@ -0,0 +1,105 @@
+use crate::blockchain::Blockchain;
+use crate::error::BlockchainError;
+use crate::transaction::PublicKey;
+use crate::wallet::Wallet;
+use anyhow::{bail, Context, Result};
+use p256::ecdsa::{signature::hazmat::PrehashVerifier, Signature};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// A chain export signed by the wallet that shared it, so the recipient can
+/// check where it came from before importing it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignedChain {
+    pub signer: PublicKey,
+    /// Hex-encoded signature over `signed_hash` of the blockchain.
+    pub signature: String,
+    pub blockchain: Blockchain,
+}
+
+impl SignedChain {
+    pub fn sign(blockchain: Blockchain, wallet: &Wallet) -> Result<Self> {
+        let hash = signed_hash(&blockchain)?;
+        Ok(SignedChain {
+            signer: PublicKey(wallet.public_key),
+            signature: hex::encode(wallet.sign_prehashed(&hash).to_bytes()),
+            blockchain,
+        })
+    }
+
+    /// Checks the bundle was signed by `expected` and hasn't been altered
+    /// since, then hands back the chain. The chain itself still needs validating.
+    pub fn open(self, expected: &PublicKey) -> Result<Blockchain> {
+        if self.signer != *expected {
+            bail!(
+                "The bundle was signed by {}, not the expected {}.",
+                self.signer.to_hex(),
+                expected.to_hex()
+            );
+        }
+        let signature = hex::decode(&self.signature)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .context(BlockchainError::InvalidInput(
+                "The bundle's signature is malformed.".to_string(),
+            ))?;
+        let hash = signed_hash(&self.blockchain)?;
+        if self.signer.0.verify_prehash(&hash, &signature).is_err() {
+            bail!("The bundle's signature doesn't match its contents. It was altered after signing.");
+        }
+        let mut blockchain = self.blockchain;
+        blockchain.migrate()?;
+        blockchain.check_limits()?;
+        Ok(blockchain)
+    }
+}
+
+/// What the signature covers: the network, every block and the difficulty.
+/// The mempool isn't included, since importing a chain never takes it.
+fn signed_hash(blockchain: &Blockchain) -> Result<Vec<u8>> {
+    let data = serde_json::to_vec(&(&blockchain.network, &blockchain.chain, blockchain.difficulty))?;
+    Ok(Sha256::digest(data).to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::{mined_chain, seeded_wallet};
+
+    fn bundle(signer: &Wallet) -> SignedChain {
+        SignedChain::sign(mined_chain(2, 1).0, signer).unwrap()
+    }
+
+    #[test]
+    fn untouched_bundles_open() {
+        let signer = seeded_wallet(3);
+        let chain = bundle(&signer).open(&PublicKey(signer.public_key)).unwrap();
+        assert_eq!(chain.chain.len(), 3);
+    }
+
+    #[test]
+    fn bundles_edited_after_signing_are_refused() {
+        let signer = seeded_wallet(3);
+        let mut edited = bundle(&signer);
+        edited.blockchain.chain[1].transactions[0].amount += 1;
+        let err = edited.open(&PublicKey(signer.public_key)).unwrap_err();
+        assert!(err.to_string().contains("altered after signing"), "{}", err);
+
+        let mut edited = bundle(&signer);
+        edited.blockchain.difficulty += 1;
+        assert!(edited.open(&PublicKey(signer.public_key)).is_err());
+    }
+
+    #[test]
+    fn bundles_from_the_wrong_signer_are_refused() {
+        let (expected, other) = (seeded_wallet(3), seeded_wallet(4));
+        let err = bundle(&other).open(&PublicKey(expected.public_key)).unwrap_err();
+        assert!(err.to_string().contains("not the expected"), "{}", err);
+
+        // Claiming to be the expected signer doesn't help without their key.
+        let mut forged = bundle(&other);
+        forged.signer = PublicKey(expected.public_key);
+        let err = forged.open(&PublicKey(expected.public_key)).unwrap_err();
+        assert!(err.to_string().contains("altered after signing"), "{}", err);
+    }
+}
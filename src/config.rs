@@ -1,136 +1,1290 @@
-use crate::{blockchain::Blockchain, wallet::Wallet};
-use anyhow::{Context, Result};
+use crate::{
+    backup::WalletBackup,
+    block::Block,
+    blockchain::{Blockchain, DUST_THRESHOLD, MAIN_NETWORK, MAX_REORG_DEPTH},
+    display::DEFAULT_SHORT_LEN,
+    error::BlockchainError,
+    storage::{describe_io_error, with_retry, FileStorage, Storage},
+    transaction::{PublicKey, Transaction},
+    wallet::{StoredWallet, Wallet},
+};
+use anyhow::{bail, Context, Result};
 use colored::*;
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs, path::PathBuf};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{self, ErrorKind, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, MutexGuard, OnceLock, PoisonError,
+    },
+};
 
 const APP_DIR: &str = "mini-blockchain";
 const CONFIG_FILE: &str = "config.json";
 const CHAIN_FILE: &str = "chain.json";
+const COMPRESSED_CHAIN_FILE: &str = "chain.json.gz";
 const WALLETS_DIR: &str = "wallets";
+const NETWORKS_DIR: &str = "networks";
 const CONTACTS_FILE: &str = "contacts.json";
+const VALIDATION_FILE: &str = "validation.json";
+const CONTACT_HISTORY_FILE: &str = "contact_history.json";
+const ACCOUNTS_FILE: &str = "accounts.json";
+const LABELS_FILE: &str = "labels.json";
+const MINING_STATE_FILE: &str = "mining_state.json";
+/// Imported files (address books, transactions) larger than this are refused before being read.
+const MAX_IMPORT_FILE_BYTES: u64 = 1024 * 1024;
+/// Supplies wallet passphrases instead of prompting, e.g. in scripts.
+pub const PASSPHRASE_ENV: &str = "MINI_BLOCKCHAIN_PASSPHRASE";
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub active_wallet: Option<String>,
+    /// Write `chain.json` without indentation. Loading accepts either form.
+    #[serde(default)]
+    pub compact_json: bool,
+    /// Store the chain gzipped as `chain.json.gz` instead of plain `chain.json`.
+    #[serde(default)]
+    pub compress_chain: bool,
+    /// Sends above this amount trigger a fat-finger warning (or are refused with `--strict`).
+    #[serde(default = "default_max_tx_amount")]
+    pub max_tx_amount: u64,
+    /// Sends of more than this percentage of the sender's spendable balance
+    /// must be confirmed (0 = never ask).
+    #[serde(default = "default_confirm_above_percent")]
+    pub confirm_above_percent: u8,
+    /// Received amounts below this are left out of the spendable balance.
+    #[serde(default = "default_dust_threshold")]
+    pub dust_threshold: u64,
+    /// Chain imports may replace at most this many local blocks.
+    #[serde(default = "default_max_reorg_depth")]
+    pub max_reorg_depth: u64,
+    /// Characters of addresses and hashes shown in tables and listings (0 = full).
+    #[serde(default = "default_addr_chars")]
+    pub addr_chars: usize,
+    /// Blocks a reward from `mine` stays locked for (0 = spendable at once).
+    #[serde(default)]
+    pub coinbase_maturity: u64,
+}
+
+pub const DEFAULT_MAX_TX_AMOUNT: u64 = 1_000_000;
+
+fn default_max_tx_amount() -> u64 {
+    DEFAULT_MAX_TX_AMOUNT
+}
+
+pub const DEFAULT_CONFIRM_ABOVE_PERCENT: u8 = 50;
+
+fn default_confirm_above_percent() -> u8 {
+    DEFAULT_CONFIRM_ABOVE_PERCENT
+}
+
+fn default_dust_threshold() -> u64 {
+    DUST_THRESHOLD
+}
+
+fn default_max_reorg_depth() -> u64 {
+    MAX_REORG_DEPTH
+}
+
+fn default_addr_chars() -> usize {
+    DEFAULT_SHORT_LEN
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            active_wallet: None,
+            compact_json: false,
+            compress_chain: false,
+            max_tx_amount: DEFAULT_MAX_TX_AMOUNT,
+            confirm_above_percent: DEFAULT_CONFIRM_ABOVE_PERCENT,
+            dust_threshold: DUST_THRESHOLD,
+            max_reorg_depth: MAX_REORG_DEPTH,
+            addr_chars: DEFAULT_SHORT_LEN,
+            coinbase_maturity: 0,
+        }
+    }
+}
+
+impl Config {
+    /// Updates a user-tunable setting by its CLI name.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "compact-json" => self.compact_json = parse_setting(key, value)?,
+            "compress-chain" => self.compress_chain = parse_setting(key, value)?,
+            "max-tx-amount" => self.max_tx_amount = parse_setting(key, value)?,
+            "confirm-above-percent" => {
+                let percent: u8 = parse_setting(key, value)?;
+                if percent > 100 {
+                    bail!(BlockchainError::InvalidInput(format!(
+                        "{} must be between 0 and 100.",
+                        key
+                    )));
+                }
+                self.confirm_above_percent = percent;
+            }
+            "dust-threshold" => self.dust_threshold = parse_setting(key, value)?,
+            "max-reorg-depth" => self.max_reorg_depth = parse_setting(key, value)?,
+            "addr-chars" => self.addr_chars = parse_setting(key, value)?,
+            "coinbase-maturity" => self.coinbase_maturity = parse_setting(key, value)?,
+            _ => bail!(BlockchainError::InvalidInput(format!(
+                "Unknown setting '{}'. Available: compact-json, compress-chain, max-tx-amount, confirm-above-percent, dust-threshold, max-reorg-depth, addr-chars, coinbase-maturity.",
+                key
+            ))),
+        }
+        Ok(())
+    }
+
+    /// Whether sending `amount` out of a spendable balance of `available`
+    /// is large enough to need an explicit confirmation.
+    pub fn is_large_send(&self, amount: u64, available: i64) -> bool {
+        self.confirm_above_percent > 0
+            && available > 0
+            && amount as u128 * 100 > available as u128 * self.confirm_above_percent as u128
+    }
+}
+
+fn parse_setting<T: std::str::FromStr>(key: &str, value: &str) -> Result<T> {
+    value.parse().map_err(|_| {
+        BlockchainError::InvalidInput(format!("'{}' isn't a valid value for {}.", value, key)).into()
+    })
+}
+
+/// How far the chain has been validated, kept next to (not inside) the chain file.
+#[derive(Debug, Serialize, Deserialize)]
+struct ValidationMark {
+    chain_id: String,
+    height: u64,
+    /// Hash of the block at `height`, so the mark is dropped if history changes.
+    hash: String,
+}
+
+/// A contact's address before it was changed with `contact update`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContactChange {
+    pub previous: String,
+    /// Unix timestamp of the change.
+    pub changed_at: i64,
 }
 
 pub struct AppState {
     pub config: Config,
     pub blockchain: Blockchain,
-    pub contacts: HashMap<String, String>,
+    pub contacts: BTreeMap<String, String>,
+    /// Earlier addresses per contact nickname, oldest first.
+    pub contact_history: BTreeMap<String, Vec<ContactChange>>,
+    /// Named groups of wallets whose balances are reported together.
+    pub accounts: BTreeMap<String, BTreeSet<String>>,
+    /// Display names for arbitrary addresses (hex), shown instead of the truncated address.
+    pub labels: BTreeMap<String, String>,
+}
+
+static NETWORK: OnceLock<String> = OnceLock::new();
+
+/// Selects the network whose data this process reads and writes. Must be
+/// called before anything touches the app directory; defaults to `main`.
+pub fn select_network(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!(BlockchainError::InvalidInput(format!(
+            "'{}' isn't a valid network name. Use letters, digits, '-' and '_'.",
+            name
+        )));
+    }
+    if NETWORK.set(name.to_string()).is_err() && current_network() != name {
+        bail!("The network was already selected as '{}'.", current_network());
+    }
+    Ok(())
+}
+
+pub fn current_network() -> &'static str {
+    NETWORK.get().map(String::as_str).unwrap_or(MAIN_NETWORK)
+}
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Turns every write to the app directory (state, wallets, mining
+/// checkpoints, `clear`) into a no-op for the rest of the process.
+pub fn enable_dry_run() {
+    DRY_RUN.store(true, Ordering::Relaxed);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
 }
 
+/// The data directory for the current network. `main` uses the app directory
+/// itself; any other network gets its own `networks/<name>` subdirectory.
 pub fn get_app_dir() -> Result<PathBuf> {
     let config_dir = dirs::config_dir().context("Could not find the system's config directory.")?;
-    let app_dir = config_dir.join(APP_DIR);
+    let mut app_dir = config_dir.join(APP_DIR);
+    if current_network() != MAIN_NETWORK {
+        app_dir = app_dir.join(NETWORKS_DIR).join(current_network());
+    }
     if !app_dir.exists() {
         fs::create_dir_all(&app_dir)?;
     }
     Ok(app_dir)
 }
 
+/// The storage backing the current network's data directory.
+pub fn default_storage() -> Result<FileStorage> {
+    Ok(FileStorage::new(get_app_dir()?))
+}
+
 pub fn load_app_state() -> Result<AppState> {
-    let app_dir = get_app_dir()?;
+    load_app_state_in(&default_storage()?)
+}
 
-    let config_path = app_dir.join(CONFIG_FILE);
-    let config = match fs::read_to_string(config_path) {
-        Ok(data) => serde_json::from_str(&data)?,
-        Err(_) => Config::default(),
-    };
+pub fn load_app_state_in(storage: &dyn Storage) -> Result<AppState> {
+    let config: Config = read_json(storage, CONFIG_FILE)?.unwrap_or_default();
 
-    let chain_path = app_dir.join(CHAIN_FILE);
-    let blockchain = match fs::read_to_string(chain_path) {
-        Ok(data) => {
+    let mut blockchain = match load_chain_in(storage)? {
+        Some(blockchain) => {
             println!("{}", "[INFO] Found saved blockchain data. Loading it now.".cyan());
-            serde_json::from_str(&data)?
+            if blockchain.network != current_network() {
+                bail!(
+                    "The saved chain belongs to the '{}' network, not '{}'.",
+                    blockchain.network,
+                    current_network()
+                );
+            }
+            blockchain
         }
-        Err(_) => {
+        None => {
             println!("{}", "[INFO] No saved blockchain found. Creating a fresh one!".yellow());
-            Blockchain::new()?
+            Blockchain::new_on_network(current_network())?
         }
     };
 
-    let contacts_path = app_dir.join(CONTACTS_FILE);
-    let contacts = match fs::read_to_string(contacts_path) {
-        Ok(data) => serde_json::from_str(&data)?,
-        Err(_) => HashMap::new(),
-    };
+    blockchain.coinbase_maturity = config.coinbase_maturity;
+
+    // A mark that doesn't match the chain (e.g. after manual edits) is ignored.
+    if let Ok(Some(mark)) = read_json::<ValidationMark>(storage, VALIDATION_FILE) {
+        let matches = mark.chain_id == blockchain.chain_id()
+            && blockchain
+                .chain
+                .get(mark.height as usize)
+                .is_some_and(|block| block.hash == mark.hash);
+        if matches {
+            blockchain.validated_height = mark.height;
+        }
+    }
+
+    let contacts = read_json(storage, CONTACTS_FILE)?.unwrap_or_default();
+    let contact_history = read_json(storage, CONTACT_HISTORY_FILE)?.unwrap_or_default();
+    let accounts = read_json(storage, ACCOUNTS_FILE)?.unwrap_or_default();
+    let labels = read_json(storage, LABELS_FILE)?.unwrap_or_default();
 
     Ok(AppState {
         config,
         blockchain,
         contacts,
+        contact_history,
+        accounts,
+        labels,
     })
 }
 
+/// Turns whatever the user typed as an address into a public key: a saved
+/// contact nickname first, then a raw hex address, then the name of a
+/// local wallet.
+pub fn resolve_address(state: &AppState, input: &str) -> Result<PublicKey> {
+    resolve_address_in(&default_storage()?, state, input)
+}
+
+pub fn resolve_address_in(storage: &dyn Storage, state: &AppState, input: &str) -> Result<PublicKey> {
+    if let Some(address) = state.contacts.get(input) {
+        return PublicKey::from_hex(address)
+            .context(format!("Contact '{}' has an invalid saved address.", input));
+    }
+    if let Ok(address) = PublicKey::from_hex(input) {
+        return Ok(address);
+    }
+    if storage.exists(&wallet_key(input))? {
+        return wallet_address_in(storage, input);
+    }
+    bail!(BlockchainError::InvalidInput(format!(
+        "'{}' isn't a saved contact, a wallet or a valid address.",
+        input
+    )))
+}
+
+/// Reads the persisted chain without touching anything else, or `None` if
+/// nothing has been saved yet.
+pub fn load_saved_blockchain() -> Result<Option<Blockchain>> {
+    load_chain_in(&default_storage()?)
+}
+
+pub fn load_chain_in(storage: &dyn Storage) -> Result<Option<Blockchain>> {
+    if let Some(compressed) = storage.read(COMPRESSED_CHAIN_FILE)? {
+        let source = storage.describe(COMPRESSED_CHAIN_FILE);
+        let data = gunzip(&compressed)
+            .map_err(|err| anyhow::Error::new(err).context(format!("{} is corrupt.", source)))?;
+        return parse_blockchain(&data, &source).map(Some);
+    }
+    match storage.read(CHAIN_FILE)? {
+        Some(data) => {
+            parse_blockchain(&String::from_utf8_lossy(&data), &storage.describe(CHAIN_FILE)).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Where the saved chain is and how many bytes it takes on disk, if there is one.
+pub fn saved_chain_size() -> Result<Option<(String, u64)>> {
+    let storage = default_storage()?;
+    for key in [COMPRESSED_CHAIN_FILE, CHAIN_FILE] {
+        if let Some(size) = storage.size(key)? {
+            return Ok(Some((storage.describe(key), size)));
+        }
+    }
+    Ok(None)
+}
+
+/// Loads a chain from an arbitrary file, e.g. one received from another node.
+/// Files ending in `.gz` are decompressed first.
+pub fn load_blockchain_file(path: &Path) -> Result<Blockchain> {
+    let data = with_retry(|| {
+        let bytes = fs::read(path)?;
+        if path.extension().is_some_and(|e| e == "gz") {
+            gunzip(&bytes)
+        } else {
+            String::from_utf8(bytes).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+        }
+    })
+    .map_err(|err| describe_io_error(err, "read a chain from", path))?;
+    parse_blockchain(&data, &path.display().to_string())
+}
+
+fn gunzip(compressed: &[u8]) -> io::Result<String> {
+    let mut data = String::new();
+    GzDecoder::new(compressed).read_to_string(&mut data)?;
+    Ok(data)
+}
+
+fn parse_blockchain(data: &str, source: &str) -> Result<Blockchain> {
+    let mut blockchain: Blockchain = serde_json::from_str(data).map_err(|err| {
+        let location = match last_block_index_before(data, err.line(), err.column()) {
+            Some(index) => format!(" (somewhere in block #{})", index),
+            None => String::new(),
+        };
+        anyhow::Error::new(err).context(format!("{} is corrupt{}.", source, location))
+    })?;
+    blockchain.migrate()?;
+    blockchain.check_limits()?;
+    Ok(blockchain)
+}
+
+/// Best-effort guess at which block a parse error is in: the last `"index"`
+/// key that appears before the error position.
+fn last_block_index_before(data: &str, line: usize, column: usize) -> Option<u64> {
+    let line_start: usize = data
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum();
+    let end = (line_start + column).min(data.len());
+    let prefix = data.get(..end)?;
+    let after_key = &prefix[prefix.rfind("\"index\"")? + "\"index\"".len()..];
+    let digits: String = after_key
+        .trim_start_matches(|c: char| c == ':' || c.is_whitespace())
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect();
+    digits.parse().ok()
+}
+
 pub fn save_app_state(state: &AppState) -> Result<()> {
-    let app_dir = get_app_dir()?;
+    if is_dry_run() {
+        return Ok(());
+    }
+    save_app_state_in(&default_storage()?, state)
+}
 
-    let config_path = app_dir.join(CONFIG_FILE);
-    let config_data = serde_json::to_string_pretty(&state.config)?;
-    fs::write(config_path, config_data)?;
+pub fn save_app_state_in(storage: &dyn Storage, state: &AppState) -> Result<()> {
+    write_json(storage, CONFIG_FILE, &state.config)?;
 
-    let chain_path = app_dir.join(CHAIN_FILE);
-    let chain_data = serde_json::to_string_pretty(&state.blockchain)?;
-    fs::write(chain_path, chain_data)?;
+    let (chain_key, stale_key) = if state.config.compress_chain {
+        (COMPRESSED_CHAIN_FILE, CHAIN_FILE)
+    } else {
+        (CHAIN_FILE, COMPRESSED_CHAIN_FILE)
+    };
+    let compact = state.config.compact_json;
+    storage.write(chain_key, &mut |writer| {
+        if state.config.compress_chain {
+            let mut encoder = GzEncoder::new(writer, Compression::default());
+            serialize_json(&mut encoder, &state.blockchain, compact)?;
+            encoder.finish()?;
+            Ok(())
+        } else {
+            serialize_json(writer, &state.blockchain, compact)
+        }
+    })?;
+    // Only one form may exist, otherwise a stale copy could be loaded later.
+    storage.remove(stale_key)?;
 
-    let contacts_path = app_dir.join(CONTACTS_FILE);
-    let contacts_data = serde_json::to_string_pretty(&state.contacts)?;
-    fs::write(contacts_path, contacts_data)?;
+    write_json(storage, CONTACTS_FILE, &state.contacts)?;
+    if !state.contact_history.is_empty() || storage.exists(CONTACT_HISTORY_FILE)? {
+        write_json(storage, CONTACT_HISTORY_FILE, &state.contact_history)?;
+    }
+    if !state.accounts.is_empty() || storage.exists(ACCOUNTS_FILE)? {
+        write_json(storage, ACCOUNTS_FILE, &state.accounts)?;
+    }
+    if !state.labels.is_empty() || storage.exists(LABELS_FILE)? {
+        write_json(storage, LABELS_FILE, &state.labels)?;
+    }
+
+    let height = state.blockchain.validated_height;
+    match state.blockchain.chain.get(height as usize) {
+        Some(block) if height > 0 => {
+            let mark = ValidationMark {
+                chain_id: state.blockchain.chain_id().to_string(),
+                height,
+                hash: block.hash.clone(),
+            };
+            write_json(storage, VALIDATION_FILE, &mark)?;
+        }
+        _ => storage.remove(VALIDATION_FILE)?,
+    }
 
     Ok(())
 }
 
-pub fn get_wallets_dir() -> Result<PathBuf> {
-    let app_dir = get_app_dir()?;
-    let wallets_dir = app_dir.join(WALLETS_DIR);
-    if !wallets_dir.exists() {
-        fs::create_dir_all(&wallets_dir)?;
+/// Streams `value` as pretty JSON into storage, so even a large chain is never
+/// held in memory as one big string. The bytes match `to_string_pretty`.
+fn write_json<T: Serialize>(storage: &dyn Storage, key: &str, value: &T) -> Result<()> {
+    storage.write(key, &mut |writer| serialize_json(writer, value, false))
+}
+
+/// Parses the JSON document under `key`, or `None` if there isn't one.
+fn read_json<T: DeserializeOwned>(storage: &dyn Storage, key: &str) -> Result<Option<T>> {
+    match storage.read(key)? {
+        Some(data) => Ok(Some(
+            serde_json::from_slice(&data).context(format!("{} is corrupt.", storage.describe(key)))?,
+        )),
+        None => Ok(None),
     }
-    Ok(wallets_dir)
 }
 
-pub fn save_wallet(name: &str, wallet: &Wallet) -> Result<()> {
-    let wallets_dir = get_wallets_dir()?;
-    let wallet_path = wallets_dir.join(format!("{}.json", name));
-    let json = serde_json::to_string_pretty(wallet)?;
-    fs::write(wallet_path, json)?;
+fn serialize_json<W: Write, T: Serialize>(writer: W, value: &T, compact: bool) -> io::Result<()> {
+    if compact {
+        serde_json::to_writer(writer, value)?;
+    } else {
+        serde_json::to_writer_pretty(writer, value)?;
+    }
     Ok(())
 }
 
+/// Outcome of merging an address book file into the local contacts.
+#[derive(Debug, Default)]
+pub struct ContactImport {
+    pub added: Vec<String>,
+    pub unchanged: Vec<String>,
+    /// Nicknames that already exist locally with a different address. These are skipped.
+    pub collisions: Vec<String>,
+}
+
+/// Writes a file the user asked for (an export, receipt, backup, ...) to
+/// `path`. Under `--dry-run` it only says what it would write.
+pub fn write_user_file(path: &Path, contents: &[u8]) -> Result<()> {
+    if is_dry_run() {
+        println!("[DRY RUN] Would write {}.", path.display());
+        return Ok(());
+    }
+    with_retry(|| fs::write(path, contents)).map_err(|err| describe_io_error(err, "write", path))
+}
+
+/// `write_user_file` for a value written as pretty JSON.
+pub fn write_user_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let mut contents = Vec::new();
+    serialize_json(&mut contents, value, false)?;
+    write_user_file(path, &contents)
+}
+
+pub fn export_contacts(contacts: &BTreeMap<String, String>, path: &Path) -> Result<()> {
+    write_user_json(path, contacts)
+}
+
+pub fn import_contacts(contacts: &mut BTreeMap<String, String>, path: &Path) -> Result<ContactImport> {
+    let data = read_import_file(path, "contacts")?;
+    let incoming: BTreeMap<String, String> = serde_json::from_str(&data)
+        .context("That file doesn't look like an exported address book.")?;
+
+    // Validate everything up front so a bad entry doesn't leave a half-merged book.
+    for (name, address) in &incoming {
+        PublicKey::from_hex(address).context(format!("Contact '{}' has a bad address.", name))?;
+    }
+
+    let mut report = ContactImport::default();
+    for (name, address) in incoming {
+        match contacts.get(&name) {
+            Some(existing) if *existing == address => report.unchanged.push(name),
+            Some(_) => report.collisions.push(name),
+            None => {
+                contacts.insert(name.clone(), address);
+                report.added.push(name);
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Outcome of restoring a wallet backup into the local wallets.
+#[derive(Debug, Default)]
+pub struct WalletRestore {
+    pub added: Vec<String>,
+    pub unchanged: Vec<String>,
+    /// Names that already exist locally with a different key. These are skipped.
+    pub collisions: Vec<String>,
+}
+
+/// Writes every wallet to `path` as a checksummed `WalletBackup`, gzipped
+/// if `compress`. Returns how many wallets it holds.
+pub fn backup_wallets(path: &Path, compress: bool) -> Result<usize> {
+    let storage = default_storage()?;
+    let mut wallets = BTreeMap::new();
+    for file_name in storage.list(WALLETS_DIR)? {
+        if let Some(name) = file_name.strip_suffix(".json") {
+            // Encrypted wallets go into the backup still encrypted.
+            let wallet = read_stored_wallet_in(&storage, name).with_context(|| {
+                format!("Wallet '{}' is unreadable, so it can't be backed up. Try `wallet repair {}`.", name, name)
+            })?;
+            wallets.insert(name.to_string(), wallet);
+        }
+    }
+    write_user_json(path, &WalletBackup::seal(&wallets, compress)?)?;
+    Ok(wallets.len())
+}
+
+/// Restores the wallets in a backup written by `backup_wallets`. The checksum
+/// and every name are checked before any wallet is written.
+pub fn restore_wallets(path: &Path) -> Result<WalletRestore> {
+    let data = read_import_file(path, "a wallet backup")?;
+    let backup: WalletBackup =
+        serde_json::from_str(&data).context("That file doesn't look like a wallet backup.")?;
+    let wallets = backup.open()?;
+    if let Some(name) = wallets
+        .keys()
+        .find(|name| name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']))
+    {
+        bail!(BlockchainError::InvalidInput(format!(
+            "The backup has a wallet named '{}', which isn't a plain file name.",
+            name
+        )));
+    }
+
+    let mut report = WalletRestore::default();
+    for (name, wallet) in wallets {
+        if !wallet_exists(&name)? {
+            save_stored_wallet(&name, &wallet)?;
+            report.added.push(name);
+        } else if wallet_address(&name).is_ok_and(|existing| existing.0 == wallet.public_key()) {
+            report.unchanged.push(name);
+        } else {
+            report.collisions.push(name);
+        }
+    }
+    Ok(report)
+}
+
+/// Reads a JSON array of signed transactions, e.g. for `import-txs`.
+pub fn load_transactions_file(path: &Path) -> Result<Vec<Transaction>> {
+    let data = read_import_file(path, "transactions")?;
+    serde_json::from_str(&data)
+        .context("That file doesn't contain a JSON array of transactions.")
+}
+
+/// Reads a user-supplied file, refusing anything over the import size limit.
+fn read_import_file(path: &Path, what: &str) -> Result<String> {
+    let size = fs::metadata(path)
+        .context(format!("Couldn't read {} from {}.", what, path.display()))?
+        .len();
+    if size > MAX_IMPORT_FILE_BYTES {
+        bail!(
+            "{} is {} bytes, more than the import limit of {}.",
+            path.display(),
+            size,
+            MAX_IMPORT_FILE_BYTES
+        );
+    }
+    with_retry(|| fs::read_to_string(path))
+        .map_err(|err| describe_io_error(err, &format!("read {} from", what), path))
+}
+
+/// The block an interrupted `mine --checkpoint` was working on, if any.
+/// An unreadable checkpoint is treated as absent; it only saves time.
+pub fn load_mining_checkpoint() -> Result<Option<Block>> {
+    Ok(read_json(&default_storage()?, MINING_STATE_FILE).unwrap_or(None))
+}
+
+pub fn save_mining_checkpoint(block: &Block) -> Result<()> {
+    if is_dry_run() {
+        return Ok(());
+    }
+    write_json(&default_storage()?, MINING_STATE_FILE, block)
+}
+
+pub fn clear_mining_checkpoint() -> Result<()> {
+    if is_dry_run() {
+        return Ok(());
+    }
+    default_storage()?.remove(MINING_STATE_FILE)
+}
+
+fn wallet_key(name: &str) -> String {
+    format!("{}/{}.json", WALLETS_DIR, name)
+}
+
+/// Wallets already read by `load_wallet_in` in this process, keyed by
+/// where the file lives (`Storage::describe`), so wallets of the same name
+/// on different networks or storages never mix. Anything that writes a
+/// wallet file drops its entry.
+static WALLET_CACHE: Mutex<BTreeMap<String, Wallet>> = Mutex::new(BTreeMap::new());
+
+fn wallet_cache() -> MutexGuard<'static, BTreeMap<String, Wallet>> {
+    WALLET_CACHE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn forget_cached_wallet(storage: &dyn Storage, name: &str) {
+    wallet_cache().remove(&storage.describe(&wallet_key(name)));
+}
+
+pub fn save_wallet(name: &str, wallet: &Wallet) -> Result<()> {
+    if is_dry_run() {
+        println!("[DRY RUN] Would save wallet '{}'.", name);
+        return Ok(());
+    }
+    save_wallet_in(&default_storage()?, name, wallet)
+}
+
+pub fn save_wallet_in(storage: &dyn Storage, name: &str, wallet: &Wallet) -> Result<()> {
+    forget_cached_wallet(storage, name);
+    write_json(storage, &wallet_key(name), wallet)
+}
+
+/// Saves the wallet with its private key encrypted under `passphrase`.
+pub fn save_wallet_encrypted(name: &str, wallet: &Wallet, passphrase: &str) -> Result<()> {
+    if is_dry_run() {
+        println!("[DRY RUN] Would save wallet '{}' encrypted.", name);
+        return Ok(());
+    }
+    save_wallet_encrypted_in(&default_storage()?, name, wallet, passphrase)
+}
+
+/// Writes the encrypted wallet, then reads it back and decrypts it, so a
+/// wallet is never left behind that `passphrase` can't unlock.
+pub fn save_wallet_encrypted_in(storage: &dyn Storage, name: &str, wallet: &Wallet, passphrase: &str) -> Result<()> {
+    forget_cached_wallet(storage, name);
+    write_encrypted_wallet(storage, &wallet_key(name), wallet, passphrase)
+}
+
+fn write_encrypted_wallet(storage: &dyn Storage, key: &str, wallet: &Wallet, passphrase: &str) -> Result<()> {
+    write_json(storage, key, &wallet.encrypt(passphrase)?)?;
+    let data = storage.read(key)?.unwrap_or_default();
+    match StoredWallet::from_slice(&data)? {
+        StoredWallet::Encrypted(stored) if stored.decrypt(passphrase)? == *wallet => Ok(()),
+        _ => bail!("{} didn't read back as saved. Check the disk before using it.", storage.describe(key)),
+    }
+}
+
+/// Where `wallet rotate` keeps the new key until the chain that pays it has
+/// been saved. Wallet listings only pick up `.json` files, so it stays hidden.
+fn rotated_wallet_key(name: &str) -> String {
+    format!("{}/{}.json.new", WALLETS_DIR, name)
+}
+
+/// Writes the replacement key for `name` next to the current wallet, which
+/// is left alone until `commit_rotated_wallet`.
+pub fn stage_rotated_wallet(name: &str, wallet: &Wallet, passphrase: Option<&str>) -> Result<()> {
+    if is_dry_run() {
+        println!("[DRY RUN] Would save the new key for wallet '{}'.", name);
+        return Ok(());
+    }
+    stage_rotated_wallet_in(&default_storage()?, name, wallet, passphrase)
+}
+
+pub fn stage_rotated_wallet_in(storage: &dyn Storage, name: &str, wallet: &Wallet, passphrase: Option<&str>) -> Result<()> {
+    let key = rotated_wallet_key(name);
+    match passphrase {
+        Some(passphrase) => write_encrypted_wallet(storage, &key, wallet, passphrase),
+        None => write_json(storage, &key, wallet),
+    }
+}
+
+/// Replaces wallet `name` with the key staged by `stage_rotated_wallet`.
+/// Only call this once the chain moving the coins to that key is saved.
+pub fn commit_rotated_wallet(name: &str) -> Result<()> {
+    if is_dry_run() {
+        return Ok(());
+    }
+    commit_rotated_wallet_in(&default_storage()?, name)
+}
+
+pub fn commit_rotated_wallet_in(storage: &dyn Storage, name: &str) -> Result<()> {
+    forget_cached_wallet(storage, name);
+    let staged = rotated_wallet_key(name);
+    let data = storage
+        .read(&staged)?
+        .with_context(|| format!("{} is missing.", storage.describe(&staged)))?;
+    storage.write(&wallet_key(name), &mut |writer| writer.write_all(&data))?;
+    storage.remove(&staged)
+}
+
+/// Writes a wallet file as is, encrypted or not, e.g. from a backup.
+fn save_stored_wallet(name: &str, wallet: &StoredWallet) -> Result<()> {
+    if is_dry_run() {
+        println!("[DRY RUN] Would save wallet '{}'.", name);
+        return Ok(());
+    }
+    let storage = default_storage()?;
+    forget_cached_wallet(&storage, name);
+    write_json(&storage, &wallet_key(name), wallet)
+}
+
+pub fn wallet_exists(name: &str) -> Result<bool> {
+    wallet_exists_in(&default_storage()?, name)
+}
+
+pub fn wallet_exists_in(storage: &dyn Storage, name: &str) -> Result<bool> {
+    storage.exists(&wallet_key(name))
+}
+
 pub fn load_wallet(name: &str) -> Result<Wallet> {
-    let wallets_dir = get_wallets_dir()?;
-    let wallet_path = wallets_dir.join(format!("{}.json", name));
-    let json_data = fs::read_to_string(&wallet_path).context(format!(
-        "Couldn't find wallet '{}'. Check the name or create a new one with `wallet new`.",
-        name
-    ))?;
-    let wallet = serde_json::from_str(&json_data)?;
+    load_wallet_in(&default_storage()?, name)
+}
+
+/// Reads a wallet, asking for its passphrase (see `wallet_passphrase`) if
+/// it's encrypted. Only the first call reads the file; later ones are
+/// served from `WALLET_CACHE` for the rest of the process.
+pub fn load_wallet_in(storage: &dyn Storage, name: &str) -> Result<Wallet> {
+    let location = storage.describe(&wallet_key(name));
+    if let Some(wallet) = wallet_cache().get(&location) {
+        return Ok(wallet.clone());
+    }
+    let (wallet, _) = unlock_wallet_in(storage, name)?;
+    wallet_cache().insert(location, wallet.clone());
     Ok(wallet)
 }
 
+/// Like `load_wallet`, but also hands back the passphrase that unlocked an
+/// encrypted wallet, so a replacement key can be saved under the same one.
+pub fn unlock_wallet(name: &str) -> Result<(Wallet, Option<String>)> {
+    unlock_wallet_in(&default_storage()?, name)
+}
+
+pub fn unlock_wallet_in(storage: &dyn Storage, name: &str) -> Result<(Wallet, Option<String>)> {
+    match read_stored_wallet_in(storage, name)? {
+        StoredWallet::Plain(wallet) => Ok((wallet, None)),
+        StoredWallet::Encrypted(wallet) => {
+            let passphrase = wallet_passphrase(name, false)?;
+            let wallet = wallet
+                .decrypt(&passphrase)
+                .with_context(|| format!("Couldn't unlock wallet '{}'.", name))?;
+            Ok((wallet, Some(passphrase)))
+        }
+    }
+}
+
+fn read_stored_wallet_in(storage: &dyn Storage, name: &str) -> Result<StoredWallet> {
+    let data = storage
+        .read(&wallet_key(name))?
+        .context(BlockchainError::WalletNotFound(name.to_string()))?;
+    StoredWallet::from_slice(&data)
+}
+
+/// A wallet's address. Never needs the passphrase, even for encrypted wallets.
+pub fn wallet_address(name: &str) -> Result<PublicKey> {
+    wallet_address_in(&default_storage()?, name)
+}
+
+pub fn wallet_address_in(storage: &dyn Storage, name: &str) -> Result<PublicKey> {
+    Ok(PublicKey(read_stored_wallet_in(storage, name)?.public_key()))
+}
+
+pub fn is_wallet_encrypted(name: &str) -> Result<bool> {
+    Ok(matches!(
+        read_stored_wallet_in(&default_storage()?, name)?,
+        StoredWallet::Encrypted(_)
+    ))
+}
+
+/// The passphrase for wallet `name`: `PASSPHRASE_ENV` if set, otherwise
+/// asked for on the terminal, twice if `confirm` (when choosing a new one).
+pub fn wallet_passphrase(name: &str, confirm: bool) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    if !io::stdin().is_terminal() {
+        bail!(
+            "Wallet '{}' needs a passphrase, but there's no terminal to ask on. Set {}.",
+            name,
+            PASSPHRASE_ENV
+        );
+    }
+    let passphrase = rpassword::prompt_password(format!("Passphrase for wallet '{}': ", name))?;
+    if confirm {
+        if passphrase.is_empty() {
+            bail!(BlockchainError::InvalidInput("The passphrase can't be empty.".to_string()));
+        }
+        if rpassword::prompt_password("Repeat the passphrase: ")? != passphrase {
+            bail!(BlockchainError::InvalidInput("The passphrases don't match.".to_string()));
+        }
+    }
+    Ok(passphrase)
+}
+
+pub fn recover_wallet(name: &str) -> Result<WalletRecovery> {
+    recover_wallet_in(&default_storage()?, name)
+}
+
+/// A wallet rebuilt by `recover_wallet_in`.
+#[derive(Debug)]
+pub struct WalletRecovery {
+    pub wallet: Wallet,
+    /// Whether the stored public key was wrong or unreadable, so the file needs rewriting.
+    pub damaged: bool,
+}
+
+/// Rebuilds a wallet from the private key in its file, re-deriving the public
+/// key. Only the private key has to be intact; the stored public key may be
+/// wrong or unreadable. Nothing is written.
+pub fn recover_wallet_in(storage: &dyn Storage, name: &str) -> Result<WalletRecovery> {
+    let data = storage
+        .read(&wallet_key(name))?
+        .context(BlockchainError::WalletNotFound(name.to_string()))?;
+    let document: serde_json::Value = serde_json::from_slice(&data)
+        .with_context(|| format!("{} isn't valid JSON, so its private key can't be recovered.", wallet_key(name)))?;
+    if document.get("encrypted_key").is_some() {
+        bail!(
+            "Wallet '{}' is encrypted. Its keys are checked whenever it's unlocked, so there's nothing to repair.",
+            name
+        );
+    }
+    let private_key = document
+        .get("signing_key")
+        .and_then(serde_json::Value::as_str)
+        .with_context(|| format!("{} has no private key to recover from.", wallet_key(name)))?;
+    let wallet = Wallet::from_private_key_hex(private_key)
+        .context("The stored private key is damaged too; the wallet can't be repaired.")?;
+    let intact = serde_json::from_slice::<Wallet>(&data)
+        .map(|stored| stored.public_key == wallet.public_key)
+        .unwrap_or(false);
+    Ok(WalletRecovery {
+        wallet,
+        damaged: !intact,
+    })
+}
+
 pub fn get_all_wallets() -> Result<Vec<(String, String)>> {
-    let wallets_dir = get_wallets_dir()?;
+    get_all_wallets_in(&default_storage()?)
+}
+
+/// Every readable wallet as `(name, address)`.
+pub fn get_all_wallets_in(storage: &dyn Storage) -> Result<Vec<(String, String)>> {
     let mut wallets = Vec::new();
-    for entry in fs::read_dir(wallets_dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_file() && path.extension().map_or(false, |e| e == "json") {
-            if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
-                let wallet = load_wallet(name)?;
-                let address = hex::encode(wallet.public_key.to_encoded_point(true));
-                wallets.push((name.to_string(), address));
+    for file_name in storage.list(WALLETS_DIR)? {
+        if let Some(name) = file_name.strip_suffix(".json") {
+            // Stray or unrelated JSON files shouldn't hide every other wallet.
+            match wallet_address_in(storage, name) {
+                Ok(address) => wallets.push((name.to_string(), address.to_hex())),
+                Err(err) => println!(
+                    "{} Skipping {}: it isn't a readable wallet ({:#}).",
+                    "[WARN]".yellow(),
+                    wallet_key(name),
+                    err
+                ),
             }
         }
     }
     Ok(wallets)
 }
 
+/// Something `diagnose_in` found wrong with the stored data.
+#[derive(Debug)]
+pub enum Issue {
+    /// A leftover `.tmp` file from an interrupted save.
+    StrayTempFile(String),
+    /// A document that can't be read as what it's supposed to hold.
+    CorruptDocument { key: String, error: String },
+    /// A wallet whose stored public key is wrong or unreadable, but whose
+    /// private key is intact.
+    DamagedWallet(String),
+    /// The saved chain loads but fails validation.
+    InvalidChain,
+    /// A new key left behind by a `wallet rotate` that was interrupted.
+    UnfinishedRotation(String),
+}
+
+impl Issue {
+    /// Whether `fix_issue` can repair it without losing data.
+    pub fn is_fixable(&self) -> bool {
+        matches!(self, Issue::StrayTempFile(_) | Issue::DamagedWallet(_))
+    }
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Issue::StrayTempFile(key) => write!(f, "{} is a leftover temp file.", key),
+            Issue::CorruptDocument { key, error } => write!(f, "{} is unreadable: {}", key, error),
+            Issue::DamagedWallet(name) => write!(
+                f,
+                "Wallet '{}' has a bad public key, but its private key is intact.",
+                name
+            ),
+            Issue::InvalidChain => write!(f, "The saved chain fails validation."),
+            Issue::UnfinishedRotation(name) => write!(
+                f,
+                "{} holds the new key from an interrupted `wallet rotate`. If the chain already pays it, rename it to {}.json; otherwise delete it.",
+                rotated_wallet_key(name),
+                name
+            ),
+        }
+    }
+}
+
+pub fn diagnose() -> Result<Vec<Issue>> {
+    diagnose_in(&default_storage()?)
+}
+
+/// Health-checks the data directory: temp files left by interrupted saves,
+/// documents that no longer parse, damaged wallets and an invalid chain.
+pub fn diagnose_in(storage: &dyn Storage) -> Result<Vec<Issue>> {
+    let mut issues = Vec::new();
+    for dir in ["", WALLETS_DIR] {
+        for name in storage.list(dir)? {
+            if name.ends_with(".tmp") {
+                let key = if dir.is_empty() { name } else { format!("{}/{}", dir, name) };
+                issues.push(Issue::StrayTempFile(key));
+            }
+        }
+    }
+    for name in storage.list(WALLETS_DIR)? {
+        if let Some(wallet) = name.strip_suffix(".json.new") {
+            issues.push(Issue::UnfinishedRotation(wallet.to_string()));
+        }
+    }
+
+    let mut check = |key: &str, result: Result<()>| {
+        if let Err(err) = result {
+            issues.push(Issue::CorruptDocument {
+                key: key.to_string(),
+                error: err.root_cause().to_string(),
+            });
+        }
+    };
+    check(CONFIG_FILE, read_json::<Config>(storage, CONFIG_FILE).map(drop));
+    check(CONTACTS_FILE, read_json::<BTreeMap<String, String>>(storage, CONTACTS_FILE).map(drop));
+    check(
+        CONTACT_HISTORY_FILE,
+        read_json::<BTreeMap<String, Vec<ContactChange>>>(storage, CONTACT_HISTORY_FILE).map(drop),
+    );
+    check(
+        ACCOUNTS_FILE,
+        read_json::<BTreeMap<String, BTreeSet<String>>>(storage, ACCOUNTS_FILE).map(drop),
+    );
+    check(LABELS_FILE, read_json::<BTreeMap<String, String>>(storage, LABELS_FILE).map(drop));
+    check(VALIDATION_FILE, read_json::<ValidationMark>(storage, VALIDATION_FILE).map(drop));
+    check(MINING_STATE_FILE, read_json::<Block>(storage, MINING_STATE_FILE).map(drop));
+    match load_chain_in(storage) {
+        Ok(Some(blockchain)) if !blockchain.is_chain_valid() => issues.push(Issue::InvalidChain),
+        Ok(_) => {}
+        Err(err) => check(CHAIN_FILE, Err(err)),
+    }
+
+    for file_name in storage.list(WALLETS_DIR)? {
+        let Some(name) = file_name.strip_suffix(".json") else {
+            continue;
+        };
+        // Encrypted wallets can't be checked without the passphrase; decrypting
+        // them checks the keys instead.
+        let healthy = match read_stored_wallet_in(storage, name) {
+            Ok(StoredWallet::Plain(wallet)) => wallet.verify().is_ok(),
+            Ok(StoredWallet::Encrypted(_)) => true,
+            Err(_) => false,
+        };
+        if healthy {
+            continue;
+        }
+        match recover_wallet_in(storage, name) {
+            Ok(_) => issues.push(Issue::DamagedWallet(name.to_string())),
+            Err(err) => issues.push(Issue::CorruptDocument {
+                key: wallet_key(name),
+                error: err.root_cause().to_string(),
+            }),
+        }
+    }
+    Ok(issues)
+}
+
+/// Repairs a fixable issue: deletes the temp file or rebuilds the wallet.
+/// Returns `false` for issues that need a person to look at them.
+pub fn fix_issue(issue: &Issue) -> Result<bool> {
+    if is_dry_run() {
+        return Ok(issue.is_fixable());
+    }
+    fix_issue_in(&default_storage()?, issue)
+}
+
+pub fn fix_issue_in(storage: &dyn Storage, issue: &Issue) -> Result<bool> {
+    match issue {
+        Issue::StrayTempFile(key) => storage.remove(key)?,
+        Issue::DamagedWallet(name) => {
+            let recovery = recover_wallet_in(storage, name)?;
+            save_wallet_in(storage, name, &recovery.wallet)?;
+        }
+        Issue::CorruptDocument { .. } | Issue::InvalidChain | Issue::UnfinishedRotation(_) => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Deletes the current network's data. `main` shares its directory with the
+/// other networks' `networks/` subdirectory, which is left alone.
 pub fn clear_all_data() -> Result<()> {
     let app_dir = get_app_dir()?;
-    if app_dir.exists() {
-        fs::remove_dir_all(app_dir).context("Whoops, failed to delete the app data directory.")?;
+    let targets: Vec<PathBuf> = if current_network() == MAIN_NETWORK {
+        fs::read_dir(&app_dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|path| path.file_name() != Some(NETWORKS_DIR.as_ref()))
+            .collect()
+    } else {
+        vec![app_dir]
+    };
+    if is_dry_run() {
+        for target in &targets {
+            println!("[DRY RUN] Would delete {}.", target.display());
+        }
+        return Ok(());
+    }
+    wallet_cache().clear();
+    for target in targets {
+        let removed = if target.is_dir() { fs::remove_dir_all(&target) } else { fs::remove_file(&target) };
+        removed.with_context(|| format!("Whoops, failed to delete {}.", target.display()))?;
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::testkit::{mined_chain, seeded_wallet};
+
+    fn chain_hashes(state: &AppState) -> Vec<String> {
+        state.blockchain.chain.iter().map(|block| block.hash.clone()).collect()
+    }
+
+    #[test]
+    fn empty_storage_loads_a_fresh_state() {
+        let state = load_app_state_in(&MemoryStorage::new()).unwrap();
+        assert_eq!(state.blockchain.chain.len(), 1);
+        assert!(state.config.active_wallet.is_none() && state.contacts.is_empty());
+    }
+
+    #[test]
+    fn app_state_round_trips_through_storage() {
+        for compress_chain in [false, true] {
+            let storage = MemoryStorage::new();
+            let mut state = load_app_state_in(&storage).unwrap();
+            let (blockchain, miner) = mined_chain(2, 3);
+            let address = PublicKey(miner.public_key).to_hex();
+            state.blockchain = blockchain;
+            state.blockchain.validate(true).unwrap();
+            state.config.active_wallet = Some("miner".to_string());
+            state.config.compress_chain = compress_chain;
+            state.config.set("coinbase-maturity", "5").unwrap();
+            state.contacts.insert("me".to_string(), address.clone());
+            state.labels.insert(address, "Miner".to_string());
+            state.accounts.insert("savings".to_string(), BTreeSet::from(["miner".to_string()]));
+            save_app_state_in(&storage, &state).unwrap();
+            assert_eq!(storage.exists(COMPRESSED_CHAIN_FILE).unwrap(), compress_chain);
+            assert_eq!(storage.exists(CHAIN_FILE).unwrap(), !compress_chain);
+
+            let loaded = load_app_state_in(&storage).unwrap();
+            assert_eq!(chain_hashes(&loaded), chain_hashes(&state));
+            assert_eq!(loaded.blockchain.validated_height, 2);
+            assert_eq!(loaded.blockchain.coinbase_maturity, 5);
+            assert_eq!(loaded.config.active_wallet.as_deref(), Some("miner"));
+            assert_eq!(loaded.contacts, state.contacts);
+            assert_eq!(loaded.labels, state.labels);
+            assert_eq!(loaded.accounts, state.accounts);
+        }
+    }
+
+    #[test]
+    fn addresses_resolve_from_contacts_hex_and_wallet_names() {
+        let storage = MemoryStorage::new();
+        let mut state = load_app_state_in(&storage).unwrap();
+        let (alice, bob) = (seeded_wallet(1), seeded_wallet(2));
+        save_wallet_in(&storage, "alice", &alice).unwrap();
+        state.contacts.insert("bob".to_string(), PublicKey(bob.public_key).to_hex());
+        let resolve = |input: &str| resolve_address_in(&storage, &state, input);
+
+        assert_eq!(resolve("bob").unwrap(), PublicKey(bob.public_key));
+        assert_eq!(resolve("alice").unwrap(), PublicKey(alice.public_key));
+        assert_eq!(resolve(&PublicKey(alice.public_key).to_hex()).unwrap(), PublicKey(alice.public_key));
+
+        // A contact shadows a wallet of the same name.
+        state.contacts.insert("alice".to_string(), PublicKey(bob.public_key).to_hex());
+        assert_eq!(resolve_address_in(&storage, &state, "alice").unwrap(), PublicKey(bob.public_key));
+
+        let err = resolve_address_in(&storage, &state, "carol").unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(BlockchainError::InvalidInput(_))));
+        assert!(err.to_string().contains("'carol' isn't a saved contact, a wallet or a valid address"), "{}", err);
+    }
+
+    #[test]
+    fn streamed_state_matches_in_memory_serialization() {
+        let dir = std::env::temp_dir().join(format!("mini-blockchain-streamed-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let file_storage = crate::storage::FileStorage::new(&dir);
+        let memory_storage = MemoryStorage::new();
+        for storage in [&file_storage as &dyn Storage, &memory_storage] {
+            for compact_json in [false, true] {
+                let mut state = load_app_state_in(storage).unwrap();
+                state.blockchain = mined_chain(2, 3).0;
+                state.config.compact_json = compact_json;
+                state.contacts.insert("me".to_string(), PublicKey(seeded_wallet(3).public_key).to_hex());
+                save_app_state_in(storage, &state).unwrap();
+
+                let chain = if compact_json {
+                    serde_json::to_string(&state.blockchain).unwrap()
+                } else {
+                    serde_json::to_string_pretty(&state.blockchain).unwrap()
+                };
+                assert_eq!(storage.read(CHAIN_FILE).unwrap().unwrap(), chain.into_bytes());
+                let config = serde_json::to_string_pretty(&state.config).unwrap();
+                assert_eq!(storage.read(CONFIG_FILE).unwrap().unwrap(), config.into_bytes());
+                let contacts = serde_json::to_string_pretty(&state.contacts).unwrap();
+                assert_eq!(storage.read(CONTACTS_FILE).unwrap().unwrap(), contacts.into_bytes());
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gzipped_and_plain_chains_load_the_same_and_gz_wins() {
+        let (blockchain, _) = mined_chain(2, 3);
+        let write_chain = |storage: &MemoryStorage, key: &str, chain: &Blockchain| {
+            storage
+                .write(key, &mut |writer| {
+                    if key == COMPRESSED_CHAIN_FILE {
+                        let mut encoder = GzEncoder::new(writer, Compression::default());
+                        serialize_json(&mut encoder, chain, false)?;
+                        encoder.finish()?;
+                        Ok(())
+                    } else {
+                        serialize_json(writer, chain, false)
+                    }
+                })
+                .unwrap();
+        };
+        let load = |key: &str| {
+            let storage = MemoryStorage::new();
+            write_chain(&storage, key, &blockchain);
+            serde_json::to_value(load_chain_in(&storage).unwrap().unwrap()).unwrap()
+        };
+        assert_eq!(load(COMPRESSED_CHAIN_FILE), load(CHAIN_FILE));
+
+        // A stale plain copy left next to the compressed one is ignored.
+        let storage = MemoryStorage::new();
+        write_chain(&storage, COMPRESSED_CHAIN_FILE, &blockchain);
+        write_chain(&storage, CHAIN_FILE, &mined_chain(1, 3).0);
+        let loaded = load_chain_in(&storage).unwrap().unwrap();
+        assert_eq!(loaded.chain.len(), 3);
+        assert_eq!(loaded.chain_id(), blockchain.chain_id());
+    }
+
+    #[test]
+    fn wallets_are_saved_listed_and_loaded() {
+        let storage = MemoryStorage::new();
+        let wallet = seeded_wallet(1);
+        save_wallet_in(&storage, "alice", &wallet).unwrap();
+        assert_eq!(load_wallet_in(&storage, "alice").unwrap(), wallet);
+        assert_eq!(wallet_address_in(&storage, "alice").unwrap(), PublicKey(wallet.public_key));
+        assert_eq!(
+            get_all_wallets_in(&storage).unwrap(),
+            vec![("alice".to_string(), PublicKey(wallet.public_key).to_hex())]
+        );
+        let missing = load_wallet_in(&storage, "bob").unwrap_err();
+        assert!(matches!(missing.downcast_ref(), Some(BlockchainError::WalletNotFound(_))));
+    }
+
+    #[test]
+    fn second_wallet_load_comes_from_the_cache() {
+        let storage = MemoryStorage::new();
+        let (first, second) = (seeded_wallet(1), seeded_wallet(2));
+        save_wallet_in(&storage, "alice", &first).unwrap();
+        assert_eq!(load_wallet_in(&storage, "alice").unwrap(), first);
+
+        // Swapped behind the cache's back: the next load doesn't see it.
+        let json = serde_json::to_vec(&second).unwrap();
+        storage.write(&wallet_key("alice"), &mut |writer| writer.write_all(&json)).unwrap();
+        assert_eq!(load_wallet_in(&storage, "alice").unwrap(), first);
+
+        save_wallet_in(&storage, "alice", &second).unwrap();
+        assert_eq!(load_wallet_in(&storage, "alice").unwrap(), second);
+    }
+
+    #[test]
+    fn wallet_cache_keeps_storages_apart() {
+        let (main, test) = (MemoryStorage::new(), MemoryStorage::new());
+        save_wallet_in(&main, "alice", &seeded_wallet(1)).unwrap();
+        save_wallet_in(&test, "alice", &seeded_wallet(2)).unwrap();
+        assert_eq!(load_wallet_in(&main, "alice").unwrap(), seeded_wallet(1));
+        assert_eq!(load_wallet_in(&test, "alice").unwrap(), seeded_wallet(2));
+    }
+
+    #[test]
+    fn encrypted_wallets_keep_their_address_readable() {
+        let storage = MemoryStorage::new();
+        let wallet = seeded_wallet(2);
+        save_wallet_encrypted_in(&storage, "vault", &wallet, "correct horse").unwrap();
+        assert_eq!(wallet_address_in(&storage, "vault").unwrap(), PublicKey(wallet.public_key));
+        let StoredWallet::Encrypted(stored) = read_stored_wallet_in(&storage, "vault").unwrap() else {
+            panic!("the wallet was stored unencrypted");
+        };
+        assert_eq!(stored.decrypt("correct horse").unwrap(), wallet);
+    }
+
+    #[test]
+    fn rotated_key_replaces_the_wallet_only_when_committed() {
+        let storage = MemoryStorage::new();
+        let (old, new) = (seeded_wallet(1), seeded_wallet(2));
+        save_wallet_in(&storage, "alice", &old).unwrap();
+        stage_rotated_wallet_in(&storage, "alice", &new, None).unwrap();
+        assert_eq!(load_wallet_in(&storage, "alice").unwrap(), old);
+        assert_eq!(get_all_wallets_in(&storage).unwrap().len(), 1);
+        assert!(matches!(diagnose_in(&storage).unwrap()[..], [Issue::UnfinishedRotation(_)]));
+
+        commit_rotated_wallet_in(&storage, "alice").unwrap();
+        assert_eq!(load_wallet_in(&storage, "alice").unwrap(), new);
+        assert!(diagnose_in(&storage).unwrap().is_empty());
+    }
+
+    #[test]
+    fn diagnose_finds_and_fixes_temp_files() {
+        let storage = MemoryStorage::new();
+        save_app_state_in(&storage, &load_app_state_in(&storage).unwrap()).unwrap();
+        storage.write("chain.json.tmp", &mut |writer| writer.write_all(b"{")).unwrap();
+        storage.write(CONTACTS_FILE, &mut |writer| writer.write_all(b"not json")).unwrap();
+
+        let issues = diagnose_in(&storage).unwrap();
+        assert_eq!(issues.len(), 2);
+        for issue in &issues {
+            assert_eq!(fix_issue_in(&storage, issue).unwrap(), issue.is_fixable());
+        }
+        assert!(!storage.exists("chain.json.tmp").unwrap());
+        assert!(matches!(diagnose_in(&storage).unwrap()[..], [Issue::CorruptDocument { .. }]));
+    }
+}
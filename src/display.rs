@@ -0,0 +1,88 @@
+use crate::blockchain::Blockchain;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// How many characters of an address or hash are shown unless configured otherwise.
+pub const DEFAULT_SHORT_LEN: usize = 10;
+
+static SHORT_LEN: AtomicUsize = AtomicUsize::new(DEFAULT_SHORT_LEN);
+
+/// Sets how many characters `short` keeps. `0` shows values in full.
+pub fn set_short_len(len: usize) {
+    SHORT_LEN.store(len, Ordering::Relaxed);
+}
+
+/// Truncates an address or hash for display, marking the cut with `...`.
+/// Values that already fit are returned unchanged.
+pub fn short(value: &str) -> String {
+    let len = SHORT_LEN.load(Ordering::Relaxed);
+    match value.get(..len) {
+        Some(prefix) if len > 0 && len < value.len() => format!("{}...", prefix),
+        _ => value.to_string(),
+    }
+}
+
+/// The label given to `address` (hex), or the address shortened with `short`.
+pub fn labeled(address: &str, labels: &BTreeMap<String, String>) -> String {
+    labels.get(address).cloned().unwrap_or_else(|| short(address))
+}
+
+/// Draws `values` as a one-line bar chart, scaled between their minimum and
+/// maximum. Long series are squeezed to at most `width` bars, each showing
+/// the highest value it covers.
+pub fn sparkline(values: &[usize], width: usize) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let (Some(&min), Some(&max)) = (values.iter().min(), values.iter().max()) else {
+        return String::new();
+    };
+    values
+        .chunks(values.len().div_ceil(width.max(1)))
+        .map(|chunk| {
+            let value = chunk.iter().copied().max().unwrap_or(min);
+            let level = if max > min {
+                (value - min) * (BARS.len() - 1) / (max - min)
+            } else {
+                0
+            };
+            BARS[level]
+        })
+        .collect()
+}
+
+/// Renders the chain's confirmed transactions as a Graphviz DOT digraph:
+/// one node per address, one edge per transaction labeled with its amount
+/// and block. Mining rewards and premines come from a separate `coinbase` node.
+/// Addresses with an entry in `labels` are named by it.
+pub fn tx_graph_dot(blockchain: &Blockchain, labels: &BTreeMap<String, String>) -> String {
+    let mut addresses = BTreeSet::new();
+    let mut edges = String::new();
+    for (height, tx) in blockchain.iter_transactions() {
+        let destination = tx.destination.to_hex();
+        let (source, style) = match &tx.source {
+            Some(source) => (source.to_hex(), ""),
+            None => ("coinbase".to_string(), ", style=dashed"),
+        };
+        let _ = writeln!(
+            edges,
+            "  \"{}\" -> \"{}\" [label=\"{} (#{})\"{}];",
+            source, destination, tx.amount, height, style
+        );
+        if tx.source.is_some() {
+            addresses.insert(source);
+        }
+        addresses.insert(destination);
+    }
+
+    let mut dot = String::from("digraph transactions {\n  rankdir=LR;\n  node [shape=box];\n");
+    dot.push_str("  \"coinbase\" [label=\"COINBASE\", shape=ellipse, style=filled, fillcolor=gold];\n");
+    for address in &addresses {
+        let label = labeled(address, labels).replace('\\', "\\\\").replace('"', "\\\"");
+        let _ = writeln!(dot, "  \"{}\" [label=\"{}\"];", address, label);
+    }
+    dot.push_str(&edges);
+    dot.push_str("}\n");
+    dot
+}
@@ -0,0 +1,42 @@
+use std::fmt::{self, Display, Formatter};
+
+/// Failures that callers need to tell apart, e.g. to pick a process exit code.
+/// Raise them with `bail!` or attach them with `.context(...)`; they survive
+/// being wrapped in an `anyhow::Error` and can be recovered with `downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockchainError {
+    /// The user supplied something unusable (bad hex, not a public key, ...).
+    InvalidInput(String),
+    /// No wallet file exists under the given name.
+    WalletNotFound(String),
+    /// The sender can't cover the requested amount.
+    InsufficientFunds { available: i64, requested: u64 },
+    /// The chain failed its integrity checks.
+    ChainInvalid,
+}
+
+impl Display for BlockchainError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            BlockchainError::InvalidInput(msg) => write!(f, "{}", msg),
+            BlockchainError::WalletNotFound(name) => write!(
+                f,
+                "Couldn't find wallet '{}'. Check the name or create a new one with `wallet new`.",
+                name
+            ),
+            BlockchainError::InsufficientFunds {
+                available,
+                requested,
+            } => write!(
+                f,
+                "Insufficient funds: tried to send {} coins but only {} are available.",
+                requested, available
+            ),
+            BlockchainError::ChainInvalid => {
+                write!(f, "The blockchain failed validation.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BlockchainError {}
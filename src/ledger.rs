@@ -0,0 +1,111 @@
+use crate::block::Block;
+use crate::error::BlockchainError;
+use crate::transaction::{PublicKey, SpendCondition, Transaction};
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet};
+
+/// Running balances built by replaying transactions block by block, used to
+/// check that every spend is funded and respects spending conditions.
+#[derive(Debug, Default)]
+pub(crate) struct Ledger {
+    balances: HashMap<PublicKey, i64>,
+    /// Coins that can't be spent yet: (owner, amount, condition).
+    restricted: Vec<(PublicKey, u64, SpendCondition)>,
+    /// Nonces each sender has used so far.
+    nonces: HashSet<(PublicKey, u64)>,
+}
+
+impl Ledger {
+    /// Replays every transaction in `blocks`, failing on the first one that
+    /// is unfunded, reuses a nonce or breaks a spending condition. The genesis block's
+    /// transactions (the premine) are credited unconditionally.
+    pub(crate) fn replay<'a>(blocks: impl IntoIterator<Item = &'a Block>) -> Result<Self> {
+        let mut ledger = Ledger::default();
+        for block in blocks {
+            for tx in &block.transactions {
+                if block.index == 0 {
+                    ledger.credit(tx, 0)?;
+                } else {
+                    ledger.apply(tx, block.index)?;
+                }
+            }
+        }
+        Ok(ledger)
+    }
+
+    pub(crate) fn balance(&self, address: &PublicKey) -> i64 {
+        self.balances.get(address).copied().unwrap_or(0)
+    }
+
+    /// Coins owned by `address` that a block at `height` may not spend yet.
+    pub(crate) fn locked(&self, address: &PublicKey, height: u64) -> u64 {
+        self.restricted
+            .iter()
+            .filter(|(owner, _, condition)| owner == address && !condition.is_satisfied(height))
+            .fold(0u64, |total, (_, amount, _)| total.saturating_add(*amount))
+    }
+
+    /// Applies `tx` as if it were included in a block at `height`.
+    pub(crate) fn apply(&mut self, tx: &Transaction, height: u64) -> Result<()> {
+        let amount = checked_amount(tx)?;
+        if let Some(source) = &tx.source {
+            if let Some(nonce) = tx.nonce {
+                if self.nonces.contains(&(source.clone(), nonce)) {
+                    bail!(BlockchainError::InvalidInput(format!(
+                        "The sender has already used nonce {}. This looks like a replayed transaction.",
+                        nonce
+                    )));
+                }
+            }
+            let available = self.balance(source);
+            let remaining = available - amount;
+            if remaining < 0 {
+                bail!(BlockchainError::InsufficientFunds {
+                    available,
+                    requested: tx.amount,
+                });
+            }
+            let locked = self.locked(source, height);
+            if (remaining as u64) < locked {
+                bail!(
+                    "{} of the sender's coins are still locked at block #{}, so it can't send {}.",
+                    locked,
+                    height,
+                    tx.amount
+                );
+            }
+            *self.balances.entry(source.clone()).or_default() = remaining;
+            if let Some(nonce) = tx.nonce {
+                self.nonces.insert((source.clone(), nonce));
+            }
+        }
+        self.credit(tx, height)
+    }
+
+    /// Adds `tx`'s amount to the recipient, failing if their balance would
+    /// overflow (only possible with crafted amounts).
+    fn credit(&mut self, tx: &Transaction, height: u64) -> Result<()> {
+        let amount = checked_amount(tx)?;
+        let balance = self.balances.entry(tx.destination.clone()).or_default();
+        *balance = balance.checked_add(amount).ok_or_else(|| {
+            BlockchainError::InvalidInput(format!(
+                "Receiving {} coins would overflow the recipient's balance.",
+                tx.amount
+            ))
+        })?;
+        if let Some(condition) = tx.condition {
+            if !condition.is_satisfied(height) {
+                self.restricted.push((tx.destination.clone(), tx.amount, condition));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `tx`'s amount as a balance change. Amounts above `i64::MAX` can't be
+/// represented in a balance, so they're rejected outright.
+fn checked_amount(tx: &Transaction) -> Result<i64> {
+    i64::try_from(tx.amount).map_err(|_| {
+        BlockchainError::InvalidInput(format!("{} coins is more than any balance can hold.", tx.amount)).into()
+    })
+}
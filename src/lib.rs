@@ -1,5 +1,19 @@
+pub mod attack;
+pub mod backup;
+pub mod benchmark;
 pub mod block;
 pub mod blockchain;
+pub mod bundle;
 pub mod config;
+pub mod display;
+pub mod error;
+mod ledger;
+pub mod merkle;
+pub mod receipt;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod storage;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
 pub mod transaction;
 pub mod wallet;
\ No newline at end of file
@@ -1,60 +1,478 @@
 use mini_blockchain::{
-    config,
-    transaction::{PublicKey, Transaction},
+    attack,
+    benchmark,
+    block::Block,
+    blockchain::{
+        Blockchain, GenesisExport, ReorgReport, Submission, TxFilter, TxLocation, VersionActivation,
+        block_reward,
+    },
+    bundle::SignedChain,
+    config::{self, Config, ContactChange},
+    display::{self, labeled, short},
+    error::BlockchainError,
+    receipt::PaymentReceipt,
+    storage::Storage,
+    transaction::{PublicKey, SpendCondition, Transaction},
     wallet::Wallet,
 };
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
 use colored::*;
 use comfy_table::{presets::UTF8_FULL, Table};
-use p256::ecdsa::VerifyingKey;
+use qrcode::{render::unicode, QrCode};
+use std::{collections::BTreeMap, io::IsTerminal, path::PathBuf, process::ExitCode, time::Duration};
 
 #[derive(Parser, Debug)]
 #[command(name = "mini-blockchain", version, about = "A fun little blockchain, written in Rust, now with all the bells and whistles!")]
 struct Cli {
+    /// Network to operate on. Each network keeps entirely separate wallets, contacts and chain.
+    #[arg(long, global = true, default_value = "main")]
+    network: String,
+    /// Characters of addresses and hashes to show in tables (0 = full). Overrides the `addr-chars` setting.
+    #[arg(long, global = true)]
+    addr_chars: Option<usize>,
+    /// Run the command in memory and report what would change, without saving anything.
+    #[arg(long, global = true)]
+    dry_run: bool,
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum WalletSort {
+    Name,
+    Balance,
+}
+
+/// What `wallet rotate` does with transactions the old key still has pending.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum PendingAction {
+    /// Sign them again with the new key, after the rotation block.
+    Resign,
+    /// Remove them from the mempool.
+    Drop,
+}
+
+/// Which attack `simulate-attack` stages.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum AttackKind {
+    /// Edit a confirmed transaction's amount without re-signing it.
+    TamperAmount,
+    /// Spend the same coins twice in one block.
+    DoubleSpend,
+    /// Move someone else's coins with a signature from the wrong key.
+    ForgeSignature,
+}
+
+/// Which side of a transaction `history` shows.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Direction {
+    Sent,
+    Received,
+    All,
+}
+
 #[derive(Subcommand, Debug)]
 enum WalletCommands {
     New { name: String },
-    List,
+    List {
+        /// Order wallets alphabetically or by on-chain balance (highest first).
+        #[arg(long, value_enum, default_value_t = WalletSort::Name)]
+        sort: WalletSort,
+    },
     Use { name: String },
+    /// Clear the active wallet, so commands that need one must be told which to use.
+    Unset,
+    /// Show a wallet's addresses, balance and activity (never its private key).
+    Info { name: String },
+    /// Check a wallet's keys match and can sign, without showing the private key.
+    Verify { name: String },
+    /// Re-derive a wallet's public key from its private key and rewrite the file.
+    Repair { name: String },
+    /// Replace a wallet's key, sweeping its balance to the new address.
+    /// Mines a block to do it, which also includes the rest of the mempool
+    /// and pays the block reward to the new key.
+    Rotate {
+        name: String,
+        /// What to do with the old key's pending transactions (asks if not given).
+        #[arg(long, value_enum)]
+        pending: Option<PendingAction>,
+    },
+    /// Write every wallet to one checksummed backup file.
+    BackupAll {
+        path: PathBuf,
+        /// Gzip the wallets inside the backup.
+        #[arg(long)]
+        compress: bool,
+    },
+    /// Restore wallets from a `backup-all` file, skipping names already in use.
+    RestoreAll { path: PathBuf },
+    /// Encrypt a wallet's private key with a passphrase.
+    Encrypt { name: String },
+    /// Store a wallet's private key unencrypted again.
+    Decrypt { name: String },
+    /// Print a wallet's private key as one checksummed line, for moving it to another machine.
+    Export { name: String },
+    /// Create a wallet from a key printed by `wallet export`.
+    Import {
+        name: String,
+        key: String,
+        /// Replace an existing wallet with this name. Its current key is lost.
+        #[arg(long)]
+        force: bool,
+    },
 }
 
 #[derive(Subcommand, Debug)]
 enum ContactCommands {
     Add { name: String, address: String },
+    /// Change a contact's address, keeping the old one in its history.
+    Update { name: String, new_address: String },
+    List {
+        /// Also show each contact's previous addresses.
+        #[arg(long)]
+        history: bool,
+    },
+    /// Write the address book to a JSON file.
+    Export { path: PathBuf },
+    /// Merge contacts from a JSON file, skipping nicknames that already exist.
+    Import { path: PathBuf },
+}
+
+#[derive(Subcommand, Debug)]
+enum AccountCommands {
+    /// Put a wallet in an account, creating the account if needed.
+    Add { account: String, wallet: String },
+    /// Take a wallet out of an account; an account left empty is deleted.
+    Remove { account: String, wallet: String },
+    /// Show every account with its wallets and combined balance.
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum LabelCommands {
+    /// Name an address (or contact) in history, find-tx, pending and tx-graph output.
+    Add { address: String, name: String },
+    /// Forget the label for an address.
+    Remove { address: String },
+    /// Show every labelled address.
     List,
 }
 
+#[derive(Subcommand, Debug)]
+enum ConfigCommands {
+    /// Print the current settings.
+    Show,
+    /// Change a setting, e.g. `config set compact-json true`.
+    Set { key: String, value: String },
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     #[command(subcommand)]
     Wallet(WalletCommands),
     #[command(subcommand)]
     Contact(ContactCommands),
+    /// Group wallets into named accounts for combined balances.
+    #[command(subcommand)]
+    Account(AccountCommands),
+    /// Give addresses readable names in explorer output.
+    #[command(subcommand)]
+    Label(LabelCommands),
+    #[command(subcommand)]
+    Config(ConfigCommands),
     AddTx {
         #[arg(short, long)]
         receiver: String,
         #[arg(short, long)]
         amount: u64,
+        /// Refuse (instead of warn about) amounts above the `max-tx-amount` setting.
+        #[arg(long)]
+        strict: bool,
+        /// Preview the transaction and ask before adding it to the mempool.
+        #[arg(long)]
+        confirm: bool,
+        /// Answer yes to any confirmation prompt.
+        #[arg(short, long)]
+        yes: bool,
+        /// Ask for confirmation when sending more than this many coins, on top
+        /// of the `confirm-above-percent` setting.
+        #[arg(long)]
+        warn_above: Option<u64>,
+        /// Resubmitting with the same key returns the original transaction instead of a duplicate.
+        #[arg(long)]
+        idempotency_key: Option<String>,
+        /// Restrict when the recipient may spend the coins, e.g. `timelock:120`.
+        #[arg(long)]
+        condition: Option<SpendCondition>,
+        /// Sign so the sender's key can be recovered from the signature instead of stored.
+        #[arg(long)]
+        recoverable: bool,
+        /// Don't let the transaction be mined before this block height.
+        #[arg(long, alias = "schedule-at", conflicts_with = "in_blocks")]
+        at_height: Option<u64>,
+        /// Don't let the transaction be mined until this many blocks after the current tip.
+        #[arg(long)]
+        in_blocks: Option<u64>,
+    },
+    Mine {
+        /// Only mine these mempool transactions (comma-separated ids); the rest stay pending.
+        #[arg(long, value_delimiter = ',')]
+        include: Vec<String>,
+        /// Print proof-of-work metrics (hashes tried, time, hash rate) afterwards.
+        #[arg(long)]
+        profile: bool,
+        /// Save progress periodically and resume an interrupted mine of the same block.
+        #[arg(long, conflicts_with = "include")]
+        checkpoint: bool,
+        /// Timestamp the block one target interval after the tip, so the same
+        /// chain and mempool always mine the same block.
+        #[arg(long, conflicts_with_all = ["include", "checkpoint"])]
+        deterministic: bool,
     },
-    Mine,
+    /// Mine the next block into a file, leaving the chain and mempool untouched.
+    MineToFile { path: PathBuf },
+    /// Validate a block file (e.g. from `mine-to-file`) and append it to the chain.
+    AppendBlock { path: PathBuf },
     Balance {
         #[arg(short, long)]
         address: Option<String>,
+        /// Add up the balances of every wallet in this account instead.
+        #[arg(long, conflicts_with_all = ["address", "watch"])]
+        account: Option<String>,
+        /// Show the balances of several addresses or contacts (comma-separated) at once.
+        #[arg(long, value_delimiter = ',', conflicts_with_all = ["address", "account", "watch"])]
+        addresses: Vec<String>,
+        /// Keep re-reading the chain and refresh the balance until Ctrl-C.
+        #[arg(short, long)]
+        watch: bool,
+        /// Seconds between refreshes in watch mode.
+        #[arg(long, default_value_t = 2, requires = "watch")]
+        interval: u64,
     },
     Pending,
-    List,
-    Validate,
+    List {
+        /// Also list each block's transaction ids.
+        #[arg(long)]
+        txs: bool,
+    },
+    /// List the confirmed transactions of an address (the active wallet's by default).
+    History {
+        #[arg(short, long)]
+        address: Option<String>,
+        /// Skip blocks below this height.
+        #[arg(long)]
+        since: Option<u64>,
+        /// Skip blocks above this height.
+        #[arg(long)]
+        until: Option<u64>,
+        #[arg(long, value_enum, default_value_t = Direction::All)]
+        direction: Direction,
+    },
+    /// List every address that has appeared on-chain, with its first block and balance.
+    Addresses,
+    Validate {
+        /// Re-check every block instead of only those added since the last validation.
+        #[arg(long)]
+        full: bool,
+        /// Also check every block's difficulty follows the retargeting rule.
+        #[arg(long)]
+        recompute_difficulty: bool,
+    },
+    /// Search the chain and mempool for transactions matching every given filter.
+    FindTx {
+        #[arg(long)]
+        from: Option<String>,
+        #[arg(long)]
+        to: Option<String>,
+        #[arg(long)]
+        min_amount: Option<u64>,
+        #[arg(long)]
+        max_amount: Option<u64>,
+        /// Only mining rewards and premine allocations.
+        #[arg(long, conflicts_with = "from")]
+        coinbase_only: bool,
+    },
+    /// Show every field of a transaction, given its id or a JSON file containing it.
+    #[command(visible_alias = "tx")]
+    TxInfo { id_or_path: String },
+    /// Re-mine blocks from a height onwards to fix broken links. Rewrites history!
+    Repair {
+        #[arg(long)]
+        from: usize,
+        /// Required: confirms you understand every later block hash will change.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Write the flow of funds between addresses as a Graphviz DOT file.
+    TxGraph { path: PathBuf },
+    /// Show an address (the active wallet's by default) as a scannable QR code.
+    Qr { address: Option<String> },
+    /// Check a chain file without loading it into (or changing) your own data.
+    VerifyChainFile { path: PathBuf },
+    /// Write a receipt proving a mined transaction, checkable without the full chain.
+    ProvePayment {
+        tx_id: String,
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Check a payment receipt produced by `prove-payment`.
+    VerifyPayment { path: PathBuf },
+    /// Measure hashing, signing and verification throughput on this machine.
+    Benchmark {
+        /// Seconds to spend on each measurement.
+        #[arg(long, default_value_t = 1)]
+        seconds: u64,
+    },
+    /// Write the genesis block to a file that `init --genesis` can start identical chains from.
+    GenesisExport { path: PathBuf },
+    /// Require blocks from a height onwards to have at least a given version,
+    /// to try out a staged protocol upgrade. Shows the current rule without arguments.
+    ActivateVersion {
+        #[arg(requires = "height", conflicts_with = "clear")]
+        min_version: Option<u32>,
+        /// First block height the rule applies to.
+        #[arg(long)]
+        height: Option<u64>,
+        /// Go back to accepting any block version.
+        #[arg(long)]
+        clear: bool,
+    },
+    /// Start a fresh chain whose genesis block pre-funds the given addresses.
+    Init {
+        /// `<address>=<amount>` to credit in the genesis block; repeatable.
+        #[arg(long)]
+        premine: Vec<String>,
+        /// Start from the genesis block in a `genesis-export` file instead of mining a new one.
+        #[arg(long, conflicts_with = "premine")]
+        genesis: Option<PathBuf>,
+        /// Required when the current chain has blocks or pending transactions to throw away.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Show an address's coin age: coins held times blocks held for.
+    CoinAge { address: String },
+    /// Print the chain id (the genesis block hash).
+    ChainId,
+    /// One-glance summary of the genesis block, the tip and the difficulty.
+    ChainInfo {
+        /// Also run full validation (slower on long chains).
+        #[arg(long)]
+        validate: bool,
+    },
+    /// Average block interval, hash rate and transaction throughput over recent blocks.
+    Rate {
+        /// How many of the most recent blocks to measure.
+        #[arg(default_value_t = 10)]
+        window: usize,
+    },
+    /// Estimate how many blocks, and roughly how long, until a pending transaction is mined.
+    EstimateConfirmation {
+        txid: String,
+        /// How many recent blocks to average the block interval over.
+        #[arg(long, default_value_t = 10)]
+        window: usize,
+    },
+    /// Report how much space the chain takes and how it is growing.
+    ChainSize {
+        /// Project the size after this many more blocks.
+        #[arg(long, default_value_t = 1000)]
+        blocks: usize,
+    },
+    /// Show how each difficulty-adjustment interval went, with a sparkline of difficulty.
+    DifficultyHistory,
+    /// Replace the local chain with a valid copy of the same chain that has more work.
+    ImportChain { path: PathBuf },
+    /// Add every transaction from a JSON array of signed transactions to the mempool.
+    ImportTxs { path: PathBuf },
+    /// Stage an attack on a copy of the chain and show that validation catches it.
+    /// The real chain is never changed.
+    SimulateAttack {
+        #[arg(value_enum)]
+        kind: AttackKind,
+    },
+    /// Drop mempool transactions that can no longer be mined against the current chain.
+    PruneMempool,
+    /// Write the chain to a file signed by the active wallet.
+    ExportSigned { path: PathBuf },
+    /// Write blocks and transactions to a SQLite database for querying.
+    #[cfg(feature = "sqlite")]
+    ExportSqlite { path: PathBuf },
+    /// Like `import-chain`, but only if the file was signed by the given address.
+    ImportSigned {
+        path: PathBuf,
+        #[arg(long)]
+        from: String,
+    },
+    /// Check the data directory for leftover temp files, corrupt files, damaged wallets and an invalid chain.
+    Doctor {
+        /// Delete leftover temp files and rebuild damaged wallets.
+        #[arg(long)]
+        fix: bool,
+    },
     Clear,
 }
 
-fn main() -> Result<()> {
-    let mut state = config::load_app_state()?;
+/// Process exit codes, so scripts can tell failures apart without parsing
+/// the error message. Argument parsing errors from clap also exit with 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExitStatus {
+    /// Everything went fine.
+    Success = 0,
+    /// Any failure without a more specific code (I/O, corrupt files, ...).
+    Failure = 1,
+    /// Malformed user input, such as an address that isn't a public key.
+    BadInput = 2,
+    /// The sender's balance can't cover the transaction.
+    InsufficientFunds = 3,
+    /// `validate` found the chain tampered with or corrupted.
+    ChainInvalid = 4,
+    /// The named wallet doesn't exist.
+    WalletNotFound = 5,
+}
+
+impl ExitStatus {
+    fn from_error(err: &anyhow::Error) -> Self {
+        match err.downcast_ref::<BlockchainError>() {
+            Some(BlockchainError::InvalidInput(_)) => ExitStatus::BadInput,
+            Some(BlockchainError::InsufficientFunds { .. }) => ExitStatus::InsufficientFunds,
+            Some(BlockchainError::ChainInvalid) => ExitStatus::ChainInvalid,
+            Some(BlockchainError::WalletNotFound(_)) => ExitStatus::WalletNotFound,
+            None => ExitStatus::Failure,
+        }
+    }
+}
+
+impl From<ExitStatus> for ExitCode {
+    fn from(status: ExitStatus) -> Self {
+        ExitCode::from(status as u8)
+    }
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitStatus::Success.into(),
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            ExitStatus::from_error(&err).into()
+        }
+    }
+}
+
+fn run() -> Result<()> {
     let cli = Cli::parse();
+    config::select_network(&cli.network)?;
+    if cli.dry_run {
+        config::enable_dry_run();
+    }
+    // Doctor has to work even when the saved state no longer loads.
+    if let Commands::Doctor { fix } = cli.command {
+        return run_doctor(fix);
+    }
+    let mut state = config::load_app_state()?;
+    display::set_short_len(cli.addr_chars.unwrap_or(state.config.addr_chars));
+    let before = cli.dry_run.then(|| Snapshot::of(&state));
     let mut state_changed = false;
 
     match cli.command {
@@ -72,30 +490,311 @@ fn main() -> Result<()> {
                         println!("{} This has been set as your active wallet.", "[INFO]".cyan());
                     }
                 }
-                WalletCommands::List => {
+                WalletCommands::List { sort } => {
                     state_changed = false;
-                    let wallets = config::get_all_wallets()?;
+                    let mut wallets = config::get_all_wallets()?
+                        .into_iter()
+                        .map(|(name, address)| {
+                            let balance = match PublicKey::from_hex(&address) {
+                                Ok(pk) => state.blockchain.get_balance(&pk)?,
+                                Err(_) => 0,
+                            };
+                            Ok((name, address, balance))
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    match sort {
+                        WalletSort::Name => wallets.sort_by(|a, b| a.0.cmp(&b.0)),
+                        WalletSort::Balance => {
+                            wallets.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)))
+                        }
+                    }
+
                     let mut table = Table::new();
-                    table.set_header(vec!["Active", "Name", "Public Address"]);
-                    for (name, address) in wallets {
+                    table.set_header(vec!["Active", "Name", "Public Address", "Balance"]);
+                    for (name, address, balance) in wallets {
                         let is_active = if state.config.active_wallet.as_deref() == Some(&name) {
                             "*".green().to_string()
                         } else {
                             "".to_string()
                         };
-                        table.add_row(vec![is_active, name.bold().to_string(), address]);
+                        table.add_row(vec![
+                            is_active,
+                            name.bold().to_string(),
+                            address,
+                            balance.to_string(),
+                        ]);
                     }
                     println!("{}", table);
                 }
                 WalletCommands::Use { name } => {
-                    config::load_wallet(&name)?;
-                    state.config.active_wallet = Some(name.clone());
+                    use_wallet(&config::default_storage()?, &mut state.config, &name)?;
                     println!(
                         "{} Your active wallet is now '{}'.",
                         "[SUCCESS]".green(),
                         name.bold()
                     );
                 }
+                WalletCommands::Unset => {
+                    match state.config.active_wallet.take() {
+                        Some(name) => println!(
+                            "{} '{}' is no longer the active wallet.",
+                            "[SUCCESS]".green(),
+                            name.bold()
+                        ),
+                        None => println!("[INFO] There was no active wallet to unset."),
+                    }
+                }
+                WalletCommands::Info { name } => {
+                    state_changed = false;
+                    let address = config::wallet_address(&name)?;
+                    let involved = state.blockchain.transactions_involving(&address).count();
+                    let active = state.config.active_wallet.as_deref() == Some(name.as_str());
+                    println!("Wallet {}{}", name.bold(), if active { " (active)" } else { "" });
+                    println!("  Address:       {}", address.to_hex());
+                    println!(
+                        "  Uncompressed:  {}",
+                        hex::encode(address.0.to_encoded_point(false))
+                    );
+                    println!("  Encrypted:     {}", if config::is_wallet_encrypted(&name)? { "yes" } else { "no" });
+                    println!("  Balance:       {}", state.blockchain.get_balance(&address)?);
+                    println!("  Pending out:   {}", state.blockchain.pending_outgoing(&address));
+                    println!("  Transactions:  {}", involved);
+                }
+                WalletCommands::Verify { name } => {
+                    state_changed = false;
+                    let wallet = config::load_wallet(&name)?;
+                    if let Err(err) = wallet.verify() {
+                        println!("{} Wallet '{}' is damaged: {}", "[INVALID]".red(), name.bold(), err);
+                        bail!("Wallet '{}' failed verification.", name);
+                    }
+                    println!(
+                        "{} Wallet '{}' is intact: its keys match and it can sign.",
+                        "[VALID]".green(),
+                        name.bold()
+                    );
+                    println!("   Address: {}", PublicKey(wallet.public_key).to_hex());
+                }
+                WalletCommands::Repair { name } => {
+                    state_changed = false;
+                    let recovery = config::recover_wallet(&name)?;
+                    let address = PublicKey(recovery.wallet.public_key).to_hex();
+                    if recovery.damaged {
+                        config::save_wallet(&name, &recovery.wallet)?;
+                        println!(
+                            "{} Rebuilt wallet '{}' from its private key.",
+                            "[SUCCESS]".green(),
+                            name.bold()
+                        );
+                    } else {
+                        println!(
+                            "[INFO] Wallet '{}' already has the right public key. Nothing to repair.",
+                            name.bold()
+                        );
+                    }
+                    println!("   Address: {}", address);
+                }
+                WalletCommands::BackupAll { path, compress } => {
+                    state_changed = false;
+                    let count = config::backup_wallets(&path, compress)?;
+                    println!(
+                        "{} Backed up {} wallets to {}.",
+                        "[SUCCESS]".green(),
+                        count,
+                        path.display()
+                    );
+                    println!("{} The backup holds private keys. Keep it somewhere safe.", "[WARN]".yellow());
+                }
+                WalletCommands::RestoreAll { path } => {
+                    state_changed = false;
+                    let report = config::restore_wallets(&path)?;
+                    println!(
+                        "{} Restored {} wallets ({} already present).",
+                        "[SUCCESS]".green(),
+                        report.added.len(),
+                        report.unchanged.len()
+                    );
+                    for name in &report.collisions {
+                        println!(
+                            "{} Skipped '{}': you already have a wallet with that name and a different key.",
+                            "[WARN]".yellow(),
+                            name.bold()
+                        );
+                    }
+                }
+                WalletCommands::Encrypt { name } => {
+                    state_changed = false;
+                    if config::is_wallet_encrypted(&name)? {
+                        bail!(BlockchainError::InvalidInput(format!("Wallet '{}' is already encrypted.", name)));
+                    }
+                    let wallet = config::load_wallet(&name)?;
+                    let passphrase = config::wallet_passphrase(&name, true)?;
+                    config::save_wallet_encrypted(&name, &wallet, &passphrase)?;
+                    println!(
+                        "{} Wallet '{}' is now encrypted. Its passphrase is needed whenever it signs.",
+                        "[SUCCESS]".green(),
+                        name.bold()
+                    );
+                    println!("{} There's no way to recover the key if you forget the passphrase.", "[WARN]".yellow());
+                }
+                WalletCommands::Decrypt { name } => {
+                    state_changed = false;
+                    if !config::is_wallet_encrypted(&name)? {
+                        bail!(BlockchainError::InvalidInput(format!("Wallet '{}' isn't encrypted.", name)));
+                    }
+                    let wallet = config::load_wallet(&name)?;
+                    config::save_wallet(&name, &wallet)?;
+                    println!(
+                        "{} Wallet '{}' is stored unencrypted again.",
+                        "[SUCCESS]".green(),
+                        name.bold()
+                    );
+                }
+                WalletCommands::Export { name } => {
+                    state_changed = false;
+                    let wallet = config::load_wallet(&name)?;
+                    println!(
+                        "{} Below is the SECRET private key of wallet '{}'. Anyone who sees it can spend every coin in the wallet.",
+                        "[WARN]".yellow().bold(),
+                        name.bold()
+                    );
+                    println!(
+                        "{} Only type it into `wallet import` on a machine you trust. Never paste it into a chat or email.",
+                        "[WARN]".yellow().bold()
+                    );
+                    println!("{}", wallet.export_key());
+                }
+                WalletCommands::Import { name, key, force } => {
+                    let wallet = Wallet::from_exported_key(&key)
+                        .map_err(|err| BlockchainError::InvalidInput(err.to_string()))?;
+                    let address = PublicKey(wallet.public_key);
+                    if config::wallet_exists(&name)? {
+                        if config::wallet_address(&name).is_ok_and(|existing| existing == address) {
+                            println!("[INFO] Wallet '{}' already holds this key. Nothing to import.", name.bold());
+                            return Ok(());
+                        }
+                        if !force {
+                            bail!(BlockchainError::InvalidInput(format!(
+                                "A wallet named '{}' already exists with a different key. Pass --force to replace it; its current key will be lost.",
+                                name
+                            )));
+                        }
+                        println!(
+                            "{} Replacing wallet '{}'. Its previous key is gone unless you backed it up.",
+                            "[WARN]".yellow(),
+                            name.bold()
+                        );
+                    }
+                    config::save_wallet(&name, &wallet)?;
+                    println!("{} Imported wallet '{}'.", "[SUCCESS]".green(), name.bold());
+                    println!("   Its public address is: {}", address.to_hex().cyan());
+                    if state.config.active_wallet.is_none() {
+                        state.config.active_wallet = Some(name.clone());
+                        println!("{} This has been set as your active wallet.", "[INFO]".cyan());
+                    }
+                }
+                WalletCommands::Rotate { name, pending } => {
+                    // The passphrase that unlocks the old key also protects the new one.
+                    let (old_wallet, passphrase) = config::unlock_wallet(&name)?;
+                    let old_address = PublicKey(old_wallet.public_key);
+                    let action = if !state.blockchain.has_pending_from(&old_address) {
+                        None
+                    } else if let Some(action) = pending {
+                        Some(action)
+                    } else if !std::io::stdin().is_terminal() {
+                        bail!(
+                            "Wallet '{}' has unconfirmed outgoing transactions. Pass --pending resign or --pending drop.",
+                            name
+                        );
+                    } else {
+                        match prompt_choice(&format!(
+                            "Wallet '{}' has unconfirmed outgoing transactions signed by the old key. (r)e-sign them with the new key, (d)rop them, or (c)ancel?",
+                            name
+                        ))? {
+                            'r' => Some(PendingAction::Resign),
+                            'd' => Some(PendingAction::Drop),
+                            _ => {
+                                println!("Operation cancelled.");
+                                return Ok(());
+                            }
+                        }
+                    };
+                    let stale = if action.is_some() {
+                        state.blockchain.take_pending_from(&old_address)
+                    } else {
+                        Vec::new()
+                    };
+
+                    let locked = state.blockchain.locked_balance(&old_address);
+                    if locked > 0 {
+                        let msg = format!(
+                            "{} of wallet '{}''s coins are still locked, so they can't be swept. They'll stay at the old address, spendable only with the old key; back it up with `wallet export` first.",
+                            locked, name
+                        );
+                        if !std::io::stdin().is_terminal() {
+                            bail!("{} Rotate again once they unlock.", msg);
+                        }
+                        if !prompt_yes_no(&format!("{} {} Continue? (y/N)", "[WARN]".yellow(), msg))? {
+                            println!("Operation cancelled.");
+                            return Ok(());
+                        }
+                    }
+
+                    // The old key stays in place until the chain paying the new
+                    // one is on disk, so an interruption can't strand the coins.
+                    let new_wallet = Wallet::new();
+                    let new_address = PublicKey(new_wallet.public_key);
+                    let swept = sweep(&mut state.blockchain, &old_wallet, &new_address)?;
+                    config::stage_rotated_wallet(&name, &new_wallet, passphrase.as_deref())?;
+
+                    println!("[INFO] Mining the rotation block... This might take a moment.");
+                    state.blockchain.mine_pending_transactions(new_address.clone())?;
+
+                    if action == Some(PendingAction::Resign) {
+                        let mut resigned = 0;
+                        for tx in &stale {
+                            let mut builder = Transaction::builder(tx.destination.clone(), tx.amount)
+                                .nonce(state.blockchain.next_nonce(&new_address));
+                            if let Some(condition) = tx.condition {
+                                builder = builder.condition(condition);
+                            }
+                            if tx.recovery_id.is_some() {
+                                builder = builder.recoverable();
+                            }
+                            if let Some(locktime) = tx.locktime {
+                                builder = builder.locktime(locktime);
+                            }
+                            if let Some(network) = &tx.network {
+                                builder = builder.network(network);
+                            }
+                            match state.blockchain.add_transaction(builder.sign(&new_wallet)) {
+                                Ok(()) => resigned += 1,
+                                Err(err) => println!(
+                                    "{} Couldn't re-sign pending transaction {}: {}",
+                                    "[WARN]".yellow(),
+                                    tx.txid(),
+                                    err
+                                ),
+                            }
+                        }
+                        println!("[INFO] Re-signed {} of {} pending transactions with the new key.", resigned, stale.len());
+                    } else if !stale.is_empty() {
+                        println!("[INFO] Dropped {} pending transactions signed by the old key.", stale.len());
+                    }
+                    config::save_app_state(&state)?;
+                    config::commit_rotated_wallet(&name)?;
+
+                    println!("{} Wallet '{}' has a fresh key.", "[SUCCESS]".green(), name.bold());
+                    println!(
+                        "   Moved {} coins from {} to {}",
+                        swept.to_string().bold(),
+                        hex::encode(old_wallet.public_key.to_encoded_point(true)).yellow(),
+                        hex::encode(new_wallet.public_key.to_encoded_point(true)).cyan()
+                    );
+                    println!(
+                        "{} The old key is gone. Don't share the old address anymore.",
+                        "[WARN]".yellow()
+                    );
+                }
             }
         }
         Commands::Contact(contact_cmd) => {
@@ -105,130 +804,1172 @@ fn main() -> Result<()> {
                     state.contacts.insert(name.clone(), address);
                     println!("{} Contact '{}' saved.", "[SUCCESS]".green(), name.bold());
                 }
-                ContactCommands::List => {
+                ContactCommands::Update { name, new_address } => {
+                    PublicKey::from_hex(&new_address)?;
+                    let Some(previous) = state.contacts.get(&name).cloned() else {
+                        bail!(BlockchainError::InvalidInput(format!(
+                            "There's no contact named '{}'. Use `contact add` to create it.",
+                            name
+                        )));
+                    };
+                    if previous == new_address {
+                        println!("[INFO] '{}' already has that address.", name);
+                        return Ok(());
+                    }
+                    state.contact_history.entry(name.clone()).or_default().push(ContactChange {
+                        previous: previous.clone(),
+                        changed_at: Utc::now().timestamp(),
+                    });
+                    state.contacts.insert(name.clone(), new_address.clone());
+                    println!("{} Updated contact '{}':", "[SUCCESS]".green(), name.bold());
+                    println!("  was {}", previous);
+                    println!("  now {}", new_address);
+                }
+                ContactCommands::List { history } => {
                     state_changed = false;
                     let mut table = Table::new();
-                    table.set_header(vec!["Nickname", "Address"]);
+                    let mut header = vec!["Nickname", "Address"];
+                    if history {
+                        header.push("Previous Addresses");
+                    }
+                    table.set_header(header);
                     for (name, address) in &state.contacts {
-                        table.add_row(vec![name.bold().to_string(), address.to_string()]);
+                        let mut row = vec![name.bold().to_string(), address.to_string()];
+                        if history {
+                            let previous = state
+                                .contact_history
+                                .get(name)
+                                .into_iter()
+                                .flatten()
+                                .map(|change| {
+                                    let when = DateTime::from_timestamp(change.changed_at, 0)
+                                        .map(|dt| dt.to_rfc2822())
+                                        .unwrap_or_default();
+                                    format!("{} (until {})", change.previous, when)
+                                })
+                                .collect::<Vec<_>>();
+                            row.push(previous.join("\n"));
+                        }
+                        table.add_row(row);
                     }
                     println!("{}", table);
                 }
+                ContactCommands::Export { path } => {
+                    state_changed = false;
+                    config::export_contacts(&state.contacts, &path)?;
+                    println!(
+                        "{} Exported {} contacts to {}.",
+                        "[SUCCESS]".green(),
+                        state.contacts.len(),
+                        path.display()
+                    );
+                }
+                ContactCommands::Import { path } => {
+                    let report = config::import_contacts(&mut state.contacts, &path)?;
+                    println!(
+                        "{} Imported {} new contacts ({} already present).",
+                        "[SUCCESS]".green(),
+                        report.added.len(),
+                        report.unchanged.len()
+                    );
+                    for name in &report.collisions {
+                        println!(
+                            "{} Skipped '{}': you already have a contact with that name and a different address.",
+                            "[WARN]".yellow(),
+                            name.bold()
+                        );
+                    }
+                }
             }
         }
-        Commands::AddTx { receiver, amount } => {
+        Commands::Label(label_cmd) => match label_cmd {
+            LabelCommands::Add { address, name } => {
+                let address = config::resolve_address(&state, &address)?.to_hex();
+                if name.trim().is_empty() {
+                    bail!(BlockchainError::InvalidInput("A label can't be empty.".to_string()));
+                }
+                state_changed = true;
+                println!("{} Labelled {} as '{}'.", "[SUCCESS]".green(), short(&address), name.bold());
+                state.labels.insert(address, name);
+            }
+            LabelCommands::Remove { address } => {
+                let address = config::resolve_address(&state, &address)?.to_hex();
+                let name = state.labels.remove(&address).context(BlockchainError::InvalidInput(format!(
+                    "{} doesn't have a label.",
+                    short(&address)
+                )))?;
+                state_changed = true;
+                println!("{} Removed the label '{}' ({}).", "[SUCCESS]".green(), name.bold(), short(&address));
+            }
+            LabelCommands::List => {
+                if state.labels.is_empty() {
+                    println!("{}", "No labels yet. Add one with `label add <address> <name>`.".italic());
+                } else {
+                    let mut table = Table::new();
+                    table.load_preset(UTF8_FULL).set_header(vec!["Label", "Address"]);
+                    for (address, name) in &state.labels {
+                        table.add_row(vec![name.clone(), address.clone()]);
+                    }
+                    println!("{}", table);
+                }
+            }
+        },
+        Commands::Account(account_cmd) => match account_cmd {
+            AccountCommands::Add { account, wallet } => {
+                if !config::wallet_exists(&wallet)? {
+                    bail!(BlockchainError::WalletNotFound(wallet));
+                }
+                if state.accounts.entry(account.clone()).or_default().insert(wallet.clone()) {
+                    state_changed = true;
+                    println!(
+                        "{} Added wallet '{}' to account '{}'.",
+                        "[SUCCESS]".green(),
+                        wallet.bold(),
+                        account.bold()
+                    );
+                } else {
+                    println!("[INFO] Wallet '{}' is already in account '{}'.", wallet, account);
+                }
+            }
+            AccountCommands::Remove { account, wallet } => {
+                let members = state.accounts.get_mut(&account).context(BlockchainError::InvalidInput(
+                    format!("There's no account named '{}'.", account),
+                ))?;
+                if !members.remove(&wallet) {
+                    bail!(BlockchainError::InvalidInput(format!(
+                        "Wallet '{}' isn't in account '{}'.",
+                        wallet, account
+                    )));
+                }
+                if members.is_empty() {
+                    state.accounts.remove(&account);
+                }
+                state_changed = true;
+                println!(
+                    "{} Removed wallet '{}' from account '{}'.",
+                    "[SUCCESS]".green(),
+                    wallet.bold(),
+                    account.bold()
+                );
+            }
+            AccountCommands::List => {
+                if state.accounts.is_empty() {
+                    println!("{}", "No accounts yet. Create one with `account add <account> <wallet>`.".italic());
+                } else {
+                    let mut table = Table::new();
+                    table
+                        .load_preset(UTF8_FULL)
+                        .set_header(vec!["Account", "Wallets", "Balance"]);
+                    for (account, members) in &state.accounts {
+                        let mut total = 0;
+                        for name in members {
+                            total += state
+                                .blockchain
+                                .get_balance(&config::wallet_address(name)?)?;
+                        }
+                        table.add_row(vec![
+                            account.clone(),
+                            members.iter().cloned().collect::<Vec<_>>().join(", "),
+                            total.to_string(),
+                        ]);
+                    }
+                    println!("{}", table);
+                }
+            }
+        },
+        Commands::Config(config_cmd) => match config_cmd {
+            ConfigCommands::Show => {
+                println!("{}", serde_json::to_string_pretty(&state.config)?);
+            }
+            ConfigCommands::Set { key, value } => {
+                state.config.set(&key, &value)?;
+                state_changed = true;
+                println!("{} {} is now {}.", "[SUCCESS]".green(), key.bold(), value);
+            }
+        },
+        Commands::AddTx {
+            receiver,
+            amount,
+            strict,
+            confirm,
+            yes,
+            warn_above,
+            idempotency_key,
+            condition,
+            recoverable,
+            at_height,
+            in_blocks,
+        } => {
+            if amount > state.config.max_tx_amount {
+                let msg = format!(
+                    "{} coins is more than the configured maximum of {}. Typo?",
+                    amount, state.config.max_tx_amount
+                );
+                if strict {
+                    bail!(BlockchainError::InvalidInput(msg));
+                }
+                println!("{} {}", "[WARN]".yellow(), msg);
+            }
+
             let active_wallet_name = state.config.active_wallet.clone().context(
                 "You don't have an active wallet. Use `wallet use <name>` to set one.",
             )?;
             let wallet = config::load_wallet(&active_wallet_name)?;
 
-            let final_receiver_addr = state.contacts.get(&receiver).unwrap_or(&receiver);
+            let receiver_pk = config::resolve_address(&state, &receiver)?;
 
-            let receiver_pk_bytes =
-                hex::decode(final_receiver_addr).context("The receiver's address isn't valid hex.")?;
-            let receiver_pk = VerifyingKey::from_sec1_bytes(&receiver_pk_bytes)
-                .context("That's not a valid public key.")?;
+            let mut builder = Transaction::builder(receiver_pk, amount)
+                .network(&state.blockchain.network)
+                .nonce(state.blockchain.next_nonce(&PublicKey(wallet.public_key)));
+            if let Some(condition) = condition {
+                builder = builder.condition(condition);
+            }
+            if recoverable {
+                builder = builder.recoverable();
+            }
+            let tip = state.blockchain.chain.len() as u64 - 1;
+            let locktime = resolve_locktime(tip, at_height, in_blocks)?;
+            if let Some(locktime) = locktime {
+                builder = builder.locktime(locktime);
+            }
+            let tx = builder.sign(&wallet);
+
+            let sender = PublicKey(wallet.public_key);
+            let available = state.blockchain.get_balance(&sender)?
+                - state.blockchain.pending_outgoing(&sender) as i64;
+            if confirm {
+                let recipient = if state.contacts.contains_key(&receiver) {
+                    format!("{} ({})", tx.destination.to_hex(), receiver.bold())
+                } else {
+                    tx.destination.to_hex()
+                };
+                println!("About to send:");
+                println!("  To:                {}", recipient);
+                println!("  Amount:            {}", amount.to_string().bold());
+                println!("  Balance afterward: {}", available.saturating_sub_unsigned(amount));
+                println!("  Transaction id:    {}", tx.txid());
+                if !yes && !prompt_yes_no("Add this transaction to the mempool? (y/n)")? {
+                    println!("Operation cancelled.");
+                    return Ok(());
+                }
+            } else if !yes
+                && (warn_above.is_some_and(|limit| amount > limit)
+                    || state.config.is_large_send(amount, available))
+            {
+                // A safety net, so it must never block a script waiting for input.
+                if !std::io::stdin().is_terminal() {
+                    bail!(
+                        "Sending {} of your {} spendable coins needs confirmation, but there's no terminal to ask on. Pass --yes to send anyway.",
+                        amount,
+                        available
+                    );
+                }
+                let prompt = format!(
+                    "You're about to send {} of your {} spendable coins. Continue? (y/n)",
+                    amount, available
+                );
+                if !prompt_yes_no(&prompt.yellow().bold().to_string())? {
+                    println!("Operation cancelled.");
+                    return Ok(());
+                }
+            }
 
-            let tx = Transaction::new(&wallet, PublicKey(receiver_pk), amount);
-            state.blockchain.add_transaction(tx)?;
+            if let Some(key) = idempotency_key {
+                if let Submission::AlreadySubmitted(txid) =
+                    state.blockchain.submit_transaction(tx, &key)?
+                {
+                    println!(
+                        "[INFO] Already submitted with this idempotency key as transaction {}.",
+                        txid
+                    );
+                    return Ok(());
+                }
+            } else {
+                state.blockchain.add_transaction(tx)?;
+            }
             state_changed = true;
-            println!(
-                "{} Transaction added to the mempool. It'll be in the next block.",
-                "[SUCCESS]".green()
-            );
+            match locktime {
+                Some(locktime) if locktime > tip + 1 => println!(
+                    "{} Transaction added to the mempool. Scheduled for height {} (current tip {}).",
+                    "[SUCCESS]".green(),
+                    locktime,
+                    tip
+                ),
+                _ => println!(
+                    "{} Transaction added to the mempool. It'll be in the next block.",
+                    "[SUCCESS]".green()
+                ),
+            }
         }
-        Commands::Mine => {
+        Commands::Mine {
+            include,
+            profile,
+            checkpoint,
+            deterministic,
+        } => {
             let active_wallet_name = state.config.active_wallet.clone()
                 .context("You need an active wallet to receive the mining reward!")?;
-            let wallet = config::load_wallet(&active_wallet_name)?;
+            let miner = config::wallet_address(&active_wallet_name)?;
+
+            if cli.dry_run {
+                let selected: Vec<Transaction> = if include.is_empty() {
+                    state.blockchain.minable_transactions()?.0
+                } else {
+                    state.blockchain.mempool.iter().filter(|tx| include.contains(&tx.txid())).cloned().collect()
+                };
+                println!(
+                    "[DRY RUN] Would mine block #{} paying {} coins to {}, with {} transactions:",
+                    state.blockchain.chain.len(),
+                    block_reward(state.blockchain.chain.len() as u64),
+                    miner.to_hex(),
+                    selected.len()
+                );
+                for tx in selected {
+                    println!("   {}", tx.txid());
+                }
+                return Ok(());
+            }
 
             println!("[INFO] Starting the miner... This might take a moment.");
-            state
-                .blockchain
-                .mine_pending_transactions(PublicKey(wallet.public_key))?;
+            let stats = if checkpoint {
+                let saved = config::load_mining_checkpoint()?;
+                let stats = state.blockchain.mine_pending_transactions_resumable(
+                    miner.clone(),
+                    saved.as_ref(),
+                    |block| {
+                        if let Err(err) = config::save_mining_checkpoint(block) {
+                            println!("{} Couldn't save mining progress: {:#}", "[WARN]".yellow(), err);
+                        }
+                    },
+                )?;
+                config::clear_mining_checkpoint()?;
+                stats
+            } else if deterministic {
+                state
+                    .blockchain
+                    .mine_pending_transactions_deterministic(miner.clone())?
+            } else if include.is_empty() {
+                state
+                    .blockchain
+                    .mine_pending_transactions(miner.clone())?
+            } else {
+                state
+                    .blockchain
+                    .mine_selected_transactions(miner.clone(), &include)?
+            };
             state_changed = true;
             println!(
                 "{} A new block has been successfully mined!",
                 "[SUCCESS]".green()
             );
+            let tip = state.blockchain.chain.last().context("The chain has no blocks.")?;
+            if let Some(reward) = tip.transactions.first().filter(|tx| tx.source.is_none()) {
+                println!(
+                    "   Reward: {} coins to {}.",
+                    reward.amount.to_string().bold(),
+                    reward.destination.to_hex()
+                );
+                println!("   {}", reward_maturity(reward, tip.index));
+            }
+            if profile {
+                println!("Proof-of-work profile:");
+                println!("  Difficulty:  {}", stats.difficulty);
+                println!("  Hashes:      {}", stats.hashes);
+                println!("  Time:        {:.3}s", stats.duration.as_secs_f64());
+                println!("  Hash rate:   {:.0} H/s", stats.hashes_per_second());
+            }
+        }
+        Commands::MineToFile { path } => {
+            let active_wallet_name = state.config.active_wallet.clone()
+                .context("You need an active wallet to receive the mining reward!")?;
+            let miner = config::wallet_address(&active_wallet_name)?;
+            let (block, stats) = state.blockchain.mine_candidate_block(miner)?;
+            config::write_user_json(&path, &block)?;
+            println!(
+                "{} Mined block #{} with {} transactions into {} ({} hashes). The chain is unchanged.",
+                "[SUCCESS]".green(),
+                block.index,
+                block.transactions.len(),
+                path.display(),
+                stats.hashes
+            );
+        }
+        Commands::AppendBlock { path } => {
+            let data = std::fs::read_to_string(&path)?;
+            let block: Block = serde_json::from_str(&data).context("That file doesn't contain a block.")?;
+            let index = block.index;
+            state.blockchain.append_block(block)?;
+            state_changed = true;
+            println!("{} Appended block #{} to the chain.", "[SUCCESS]".green(), index);
         }
-        Commands::Balance { address } => {
-            let target_address_str = match address {
-                Some(addr) => state.contacts.get(&addr).cloned().unwrap_or(addr),
+        Commands::Balance {
+            address,
+            account,
+            addresses,
+            watch,
+            interval,
+        } => {
+            if !addresses.is_empty() {
+                let keys = addresses
+                    .iter()
+                    .map(|address| config::resolve_address(&state, address))
+                    .collect::<Result<Vec<_>>>()?;
+                let balances = state.blockchain.get_balances(&keys)?;
+                let mut table = Table::new();
+                table.load_preset(UTF8_FULL).set_header(vec!["Address", "Balance"]);
+                for (input, key) in addresses.iter().zip(&keys) {
+                    table.add_row(vec![input.clone(), balances[key].to_string()]);
+                }
+                println!("{}", table);
+                return Ok(());
+            }
+            if let Some(account) = account {
+                let members = state.accounts.get(&account).context(BlockchainError::InvalidInput(
+                    format!("There's no account named '{}'.", account),
+                ))?;
+                let keys = members
+                    .iter()
+                    .map(|name| config::wallet_address(name))
+                    .collect::<Result<Vec<_>>>()?;
+                let balances = state.blockchain.get_balances(&keys)?;
+                let mut total = 0;
+                for (name, key) in members.iter().zip(&keys) {
+                    let balance = balances[key];
+                    total += balance;
+                    println!("  {:<20} {}", name, balance);
+                }
+                println!(
+                    "Balance for account {}: {} coins across {} wallets.",
+                    account.yellow(),
+                    total.to_string().bold(),
+                    members.len()
+                );
+                return Ok(());
+            }
+
+            let public_key = match address {
+                Some(addr) => config::resolve_address(&state, &addr)?,
                 None => {
                     let active_wallet_name = state.config.active_wallet.as_ref()
                         .context("No active wallet. Specify an address with `-a <address>`.")?;
-                    let wallet = config::load_wallet(active_wallet_name)?;
-                    hex::encode(wallet.public_key.to_encoded_point(true))
+                    config::wallet_address(active_wallet_name)?
                 }
             };
+            let target_address_str = public_key.to_hex();
+
+            if watch {
+                // Only ever reads the chain file, so it's safe to leave running
+                // next to another process that's mining.
+                loop {
+                    let balance = config::load_saved_blockchain()?
+                        .map(|chain| chain.get_balance(&public_key))
+                        .transpose()?
+                        .unwrap_or(0);
+                    print!("\x1B[2J\x1B[1;1H");
+                    println!(
+                        "Balance for {}: {} coins.",
+                        target_address_str.yellow(),
+                        balance.to_string().bold()
+                    );
+                    println!(
+                        "{}",
+                        format!("Refreshing every {}s. Press Ctrl-C to stop.", interval).italic()
+                    );
+                    std::thread::sleep(Duration::from_secs(interval.max(1)));
+                }
+            }
 
-            let pk_bytes = hex::decode(&target_address_str)?;
-            let public_key = VerifyingKey::from_sec1_bytes(&pk_bytes)?;
-            let balance = state.blockchain.get_balance(&PublicKey(public_key));
+            let balance = state.blockchain.get_balance(&public_key)?;
             println!(
                 "Balance for {}: {} coins.",
                 target_address_str.yellow(),
                 balance.to_string().bold()
             );
+            let spendable = state
+                .blockchain
+                .spendable_balance(&public_key, state.config.dust_threshold);
+            if spendable as i64 != balance {
+                println!(
+                    "{} of them are spendable; the rest is dust (amounts under {}).",
+                    spendable, state.config.dust_threshold
+                );
+            }
+            let locked = state.blockchain.locked_balance(&public_key);
+            if locked > 0 {
+                println!("{} of them are still locked and can't be spent yet.", locked);
+            }
         }
         Commands::Pending => {
             let mut table = Table::new();
             table
                 .load_preset(UTF8_FULL)
-                .set_header(vec!["From", "To", "Amount"]);
+                .set_header(vec!["ID", "From", "To", "Amount"]);
             if state.blockchain.mempool.is_empty() {
                 println!("{}", "The mempool is currently empty. No pending transactions.".italic());
             } else {
                 for tx in &state.blockchain.mempool {
-                    let from = tx.source.as_ref().map(|s| hex::encode(s.0.to_encoded_point(true))).unwrap_or_else(|| "COINBASE".to_string());
-                    let to = hex::encode(tx.destination.0.to_encoded_point(true));
+                    let from = tx.source.as_ref().map(|s| labeled(&s.to_hex(), &state.labels)).unwrap_or_else(|| "COINBASE".to_string());
                     table.add_row(vec![
-                        format!("{}...", &from[..10]),
-                        format!("{}...", &to[..10]),
+                        tx.txid(),
+                        from,
+                        labeled(&tx.destination.to_hex(), &state.labels),
                         tx.amount.to_string().green().to_string(),
                     ]);
                 }
                 println!("Pending Transactions in the Mempool:\n{}", table);
             }
         }
-        Commands::List => {
+        Commands::List { txs } => {
             let mut table = Table::new();
-            table
-                .load_preset(UTF8_FULL)
-                .set_header(vec!["Index", "Hash", "# Txs", "Difficulty"]);
+            let mut header = vec!["Index", "Hash", "# Txs", "Difficulty"];
+            if txs {
+                header.push("Transactions");
+            }
+            table.load_preset(UTF8_FULL).set_header(header);
             for block in &state.blockchain.chain {
-                table.add_row(vec![
+                let mut row = vec![
                     block.index.to_string().cyan().to_string(),
-                    format!("{}...", &block.hash[..10]),
+                    short(&block.hash),
                     block.transactions.len().to_string().yellow().to_string(),
                     block.difficulty.to_string(),
-                ]);
+                ];
+                if txs {
+                    let lines: Vec<String> = block
+                        .transactions
+                        .iter()
+                        .map(|tx| {
+                            let from = tx.source.as_ref().map(|s| labeled(&s.to_hex(), &state.labels)).unwrap_or_else(|| "COINBASE".to_string());
+                            format!("{}  {} -> {}", tx.txid(), from, labeled(&tx.destination.to_hex(), &state.labels))
+                        })
+                        .collect();
+                    row.push(lines.join("\n"));
+                }
+                table.add_row(row);
             }
             println!("Full Blockchain History:\n{}", table);
         }
-        Commands::Validate => {
-            if state.blockchain.is_chain_valid() {
+        Commands::History {
+            address,
+            since,
+            until,
+            direction,
+        } => {
+            let owner = match address {
+                Some(address) => config::resolve_address(&state, &address)?,
+                None => {
+                    let name = state.config.active_wallet.clone().context(
+                        "You don't have an active wallet. Use `wallet use <name>` or pass --address.",
+                    )?;
+                    config::wallet_address(&name)?
+                }
+            };
+            if let (Some(since), Some(until)) = (since, until) {
+                if since > until {
+                    bail!(BlockchainError::InvalidInput(format!(
+                        "--since {} is after --until {}.",
+                        since, until
+                    )));
+                }
+            }
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .set_header(vec!["Block", "ID", "Direction", "Counterparty", "Amount"]);
+            let mut shown = 0;
+            for (height, tx) in state.blockchain.transactions_involving(&owner) {
+                if since.is_some_and(|since| height < since) || until.is_some_and(|until| height > until) {
+                    continue;
+                }
+                let sent = tx.source.as_ref() == Some(&owner);
+                let received = tx.destination == owner;
+                let (label, counterparty, amount) = match (sent, received) {
+                    (true, true) => ("self", labeled(&owner.to_hex(), &state.labels), tx.amount.to_string()),
+                    (true, false) => (
+                        "sent",
+                        labeled(&tx.destination.to_hex(), &state.labels),
+                        format!("-{}", tx.amount).red().to_string(),
+                    ),
+                    _ => (
+                        "received",
+                        tx.source.as_ref().map(|s| labeled(&s.to_hex(), &state.labels)).unwrap_or_else(|| "COINBASE".to_string()),
+                        format!("+{}", tx.amount).green().to_string(),
+                    ),
+                };
+                let wanted = match direction {
+                    Direction::Sent => sent,
+                    Direction::Received => received,
+                    Direction::All => true,
+                };
+                if !wanted {
+                    continue;
+                }
+                table.add_row(vec![
+                    height.to_string().cyan().to_string(),
+                    tx.txid(),
+                    label.to_string(),
+                    counterparty,
+                    amount,
+                ]);
+                shown += 1;
+            }
+            if shown == 0 {
+                println!("{}", "No transactions match.".italic());
+            } else {
+                println!("{} transactions for {}:\n{}", shown, owner.to_hex(), table);
+            }
+        }
+        Commands::Addresses => {
+            let addresses = state.blockchain.addresses();
+            let mut table = Table::new();
+            table
+                .load_preset(UTF8_FULL)
+                .set_header(vec!["Address", "First Seen", "Balance"]);
+            for summary in &addresses {
+                let name = state
+                    .contacts
+                    .iter()
+                    .find(|(_, address)| **address == summary.address.to_hex())
+                    .map(|(name, _)| format!(" ({})", name))
+                    .unwrap_or_default();
+                table.add_row(vec![
+                    format!("{}{}", summary.address.to_hex(), name),
+                    format!("#{}", summary.first_seen),
+                    summary.balance.to_string(),
+                ]);
+            }
+            println!("{} distinct addresses on the chain:\n{}", addresses.len(), table);
+        }
+        Commands::Validate {
+            full,
+            recompute_difficulty,
+        } => {
+            if recompute_difficulty {
+                if let Some((height, expected, recorded)) = state.blockchain.difficulty_mismatch() {
+                    println!(
+                        "{} Block #{} records difficulty {}, but the retargeting rule gives {}.",
+                        "[INVALID]".red(),
+                        height,
+                        recorded,
+                        expected
+                    );
+                    bail!(BlockchainError::ChainInvalid);
+                }
+            }
+            let previous_mark = state.blockchain.validated_height;
+            let result = validate_chain(&mut state.blockchain, full);
+            state_changed = state.blockchain.validated_height != previous_mark;
+            result?;
+        }
+        Commands::FindTx {
+            from,
+            to,
+            min_amount,
+            max_amount,
+            coinbase_only,
+        } => {
+            let filter = TxFilter {
+                from: from.map(|addr| config::resolve_address(&state, &addr)).transpose()?,
+                to: to.map(|addr| config::resolve_address(&state, &addr)).transpose()?,
+                min_amount,
+                max_amount,
+                coinbase_only,
+            };
+            let matches = state.blockchain.search_transactions(&filter);
+            if matches.is_empty() {
+                println!("{}", "No transactions match.".italic());
+            } else {
+                let mut table = Table::new();
+                table
+                    .load_preset(UTF8_FULL)
+                    .set_header(vec!["ID", "Location", "From", "To", "Amount"]);
+                for (location, tx) in &matches {
+                    let location = match location {
+                        TxLocation::Block(height) => format!("block #{}", height),
+                        TxLocation::Mempool => "mempool".to_string(),
+                    };
+                    let from = tx.source.as_ref().map(|s| labeled(&s.to_hex(), &state.labels)).unwrap_or_else(|| "COINBASE".to_string());
+                    table.add_row(vec![
+                        tx.txid(),
+                        location,
+                        from,
+                        labeled(&tx.destination.to_hex(), &state.labels),
+                        tx.amount.to_string(),
+                    ]);
+                }
+                println!("{} matching transactions:\n{}", matches.len(), table);
+            }
+        }
+        Commands::TxInfo { id_or_path } => {
+            let path = PathBuf::from(&id_or_path);
+            let tx = if path.is_file() {
+                let data = std::fs::read_to_string(&path)?;
+                serde_json::from_str::<Transaction>(&data)
+                    .context("That file doesn't contain a serialized transaction.")?
+            } else {
+                state
+                    .blockchain
+                    .find_transaction(&id_or_path)
+                    .map(|(_, tx)| tx.clone())
+                    .context(BlockchainError::InvalidInput(format!(
+                        "No transaction with id {} on the chain or in the mempool.",
+                        id_or_path
+                    )))?
+            };
+            print_tx_info(&state.blockchain, &tx);
+        }
+        Commands::Repair { from, force } => {
+            println!(
+                "{} Repairing rewrites history: every block from #{} onwards gets a new hash, and any copies of this chain elsewhere will no longer match.",
+                "[WARN]".yellow(),
+                from
+            );
+            if !force {
+                bail!("Refusing to repair without --force.");
+            }
+            let repaired = state.blockchain.repair_from(from)?;
+            state_changed = true;
+            println!("{} Re-mined {} blocks.", "[SUCCESS]".green(), repaired);
+        }
+        Commands::TxGraph { path } => {
+            config::write_user_file(&path, display::tx_graph_dot(&state.blockchain, &state.labels).as_bytes())?;
+            println!(
+                "{} Wrote the transaction graph to {}. Render it with `dot -Tsvg {} -o graph.svg`.",
+                "[SUCCESS]".green(),
+                path.display(),
+                path.display()
+            );
+        }
+        Commands::Qr { address } => {
+            let public_key = match address {
+                Some(addr) => config::resolve_address(&state, &addr)?,
+                None => {
+                    let active_wallet_name = state.config.active_wallet.as_ref()
+                        .context("No active wallet. Pass an address or contact name.")?;
+                    config::wallet_address(active_wallet_name)?
+                }
+            };
+            let address = public_key.to_hex();
+            let code = QrCode::new(address.as_bytes())?;
+            let image = code
+                .render::<unicode::Dense1x2>()
+                .dark_color(unicode::Dense1x2::Light)
+                .light_color(unicode::Dense1x2::Dark)
+                .build();
+            println!("{}\n{}", image, address.cyan());
+        }
+        Commands::VerifyChainFile { path } => {
+            let candidate = config::load_blockchain_file(&path)?;
+            let tx_count: usize = candidate.chain.iter().map(|b| b.transactions.len()).sum();
+            let tip = candidate.chain.last().context("That chain has no blocks at all.")?;
+            println!("Chain file {}", path.display().to_string().bold());
+            println!("  Chain id:     {}", candidate.chain_id());
+            println!("  Blocks:       {}", candidate.chain.len());
+            println!("  Transactions: {}", tx_count);
+            println!("  Pending:      {}", candidate.mempool.len());
+            println!("  Tip:          #{} {}", tip.index, tip.hash);
+            println!("  Difficulty:   {}", candidate.difficulty);
+            println!("  Total work:   {}", candidate.total_work());
+            if candidate.chain_id() == state.blockchain.chain_id() {
+                println!("  Same chain as yours ({} local blocks).", state.blockchain.chain.len());
+            } else {
+                println!("  {}", "Unrelated to your chain (different genesis).".yellow());
+            }
+            if candidate.is_chain_valid() {
+                println!("{} The chain file is valid.", "[VALID]".green());
+            } else {
+                println!("{} The chain file has been tampered with or is corrupted!", "[INVALID]".red());
+                bail!(BlockchainError::ChainInvalid);
+            }
+        }
+        Commands::ProvePayment { tx_id, output } => {
+            let receipt = PaymentReceipt::new(&state.blockchain, &tx_id)?;
+            config::write_user_json(&output, &receipt)?;
+            println!(
+                "{} Receipt for {} (block #{}) written to {}.",
+                "[SUCCESS]".green(),
+                tx_id,
+                receipt.block_index(),
+                output.display()
+            );
+        }
+        Commands::VerifyPayment { path } => {
+            let data = std::fs::read_to_string(&path)?;
+            let receipt: PaymentReceipt =
+                serde_json::from_str(&data).context("That file isn't a payment receipt.")?;
+            let tx = receipt.verify()?;
+            println!(
+                "{} {} coins paid to {} in block #{}.",
+                "[VALID]".green(),
+                tx.amount,
+                tx.destination.to_hex(),
+                receipt.block_index()
+            );
+            if receipt.is_on_chain(&state.blockchain) {
+                println!("   The block is part of your chain.");
+            } else {
+                println!(
+                    "   {}",
+                    "The block is NOT part of your chain. Sync before trusting it.".yellow()
+                );
+            }
+        }
+        Commands::Benchmark { seconds } => {
+            println!("[INFO] Running benchmarks ({}s each)...", seconds.max(1));
+            let results = benchmark::run(Duration::from_secs(seconds.max(1)));
+            println!("  SHA-256 block hashes: {:>10.0} /s", results.hashes_per_second);
+            println!("  ECDSA signatures:     {:>10.0} /s", results.signs_per_second);
+            println!("  ECDSA verifications:  {:>10.0} /s", results.verifies_per_second);
+        }
+        Commands::Init { premine, genesis, force } => {
+            if (state.blockchain.chain.len() > 1 || !state.blockchain.mempool.is_empty()) && !force {
+                bail!("Your chain already has blocks or pending transactions. Pass --force to discard them.");
+            }
+            let mut allocations = Vec::new();
+            for entry in &premine {
+                let (address, amount) = entry.split_once('=').context(BlockchainError::InvalidInput(
+                    format!("'{}' should look like <address>=<amount>.", entry),
+                ))?;
+                let amount = amount.parse::<u64>().context(BlockchainError::InvalidInput(
+                    format!("'{}' isn't a valid amount.", amount),
+                ))?;
+                allocations.push((config::resolve_address(&state, address)?, amount));
+            }
+            state.blockchain = match genesis {
+                Some(path) => {
+                    let data = std::fs::read_to_string(&path)?;
+                    let export: GenesisExport =
+                        serde_json::from_str(&data).context("That file isn't a genesis export.")?;
+                    if export.network != config::current_network() {
+                        bail!(
+                            "That genesis block belongs to the '{}' network, not '{}'. Pass --network {}.",
+                            export.network,
+                            config::current_network(),
+                            export.network
+                        );
+                    }
+                    Blockchain::from_genesis(export)?
+                }
+                None => Blockchain::new_with_premine(config::current_network(), &allocations)?,
+            };
+            state_changed = true;
+            println!(
+                "{} Started a new chain {} with {} premined address(es).",
+                "[SUCCESS]".green(),
+                short(state.blockchain.chain_id()),
+                state.blockchain.chain[0].transactions.len()
+            );
+        }
+        Commands::GenesisExport { path } => {
+            let export = state.blockchain.genesis_export();
+            config::write_user_json(&path, &export)?;
+            println!(
+                "{} Wrote the genesis block of chain {} to {}.",
+                "[SUCCESS]".green(),
+                short(state.blockchain.chain_id()),
+                path.display()
+            );
+        }
+        Commands::ActivateVersion {
+            min_version,
+            height,
+            clear,
+        } => {
+            if clear {
+                if state.blockchain.version_activation.take().is_none() {
+                    println!("[INFO] There's no version activation rule to clear.");
+                } else {
+                    state.blockchain.validated_height = 0;
+                    state_changed = true;
+                    println!("{} Blocks of any version are accepted again.", "[SUCCESS]".green());
+                }
+            } else if let (Some(min_block_version), Some(height)) = (min_version, height) {
+                if min_block_version == 0 {
+                    bail!(BlockchainError::InvalidInput("Block versions start at 1.".to_string()));
+                }
+                state.blockchain.version_activation = Some(VersionActivation {
+                    height,
+                    min_block_version,
+                });
+                state.blockchain.validated_height = 0;
+                state_changed = true;
                 println!(
-                    "{} The blockchain is valid and its integrity is intact!",
-                    "[VALID]".green()
+                    "{} Blocks from #{} on must be at least version {}.",
+                    "[SUCCESS]".green(),
+                    height,
+                    min_block_version
                 );
+                let outdated = state
+                    .blockchain
+                    .chain
+                    .iter()
+                    .filter(|block| block.index >= height && block.version < min_block_version)
+                    .count();
+                if outdated > 0 {
+                    println!(
+                        "{} {} existing block(s) are older than that, so the chain will no longer validate.",
+                        "[WARN]".yellow(),
+                        outdated
+                    );
+                }
             } else {
+                match state.blockchain.version_activation {
+                    Some(activation) => println!(
+                        "Blocks from #{} on must be at least version {}.",
+                        activation.height, activation.min_block_version
+                    ),
+                    None => println!("No version activation rule is set; any block version is accepted."),
+                }
+            }
+        }
+        Commands::CoinAge { address } => {
+            let public_key = config::resolve_address(&state, &address)?;
+            println!(
+                "Coin age for {}: {} coin-blocks ({} coins held).",
+                public_key.to_hex().yellow(),
+                state.blockchain.coin_age(&public_key).to_string().bold(),
+                state.blockchain.get_balance(&public_key)?
+            );
+        }
+        Commands::ChainId => {
+            println!("{}", state.blockchain.chain_id());
+        }
+        Commands::ChainInfo { validate } => {
+            let blockchain = &state.blockchain;
+            let tip = blockchain.chain.last().context("The chain has no blocks at all.")?;
+            let tip_time = DateTime::from_timestamp(tip.timestamp, 0)
+                .map(|dt| dt.to_rfc2822())
+                .unwrap_or_default();
+            println!("Chain id:    {}", blockchain.chain_id());
+            println!("Network:     {}", blockchain.network);
+            println!("Blocks:      {}", blockchain.chain.len());
+            println!("Tip:         #{} {}", tip.index, tip.hash);
+            println!("Tip mined:   {}", tip_time);
+            println!("Difficulty:  {}", blockchain.difficulty);
+            if let Some(activation) = blockchain.version_activation {
                 println!(
-                    "{} DANGER: The blockchain has been tampered with or is corrupted!",
-                    "[INVALID]".red()
+                    "Versions:    v{}+ from #{}",
+                    activation.min_block_version, activation.height
                 );
             }
+            if !validate {
+                println!("Validity:    not checked (use --validate)");
+            } else if blockchain.is_chain_valid() {
+                println!("Validity:    {}", "valid".green());
+            } else {
+                println!("Validity:    {}", "INVALID".red());
+                bail!(BlockchainError::ChainInvalid);
+            }
+        }
+        Commands::Rate { window } => {
+            let rate = state
+                .blockchain
+                .rate(window)
+                .context("There are no blocks after genesis to measure yet.")?;
+            println!("Over the last {} blocks:", rate.blocks);
+            println!("  Average block interval:  {:.1}s", rate.average_interval_secs());
+            println!("  Network hash rate:       {:.0} H/s", rate.hashes_per_second());
+            println!("  Transactions per block:  {:.2}", rate.transactions_per_block());
+            println!("  Transactions per second: {:.3}", rate.transactions_per_second());
         }
+        Commands::EstimateConfirmation { txid, window } => {
+            let Some(blocks) = state.blockchain.blocks_until_mined(&txid)? else {
+                println!(
+                    "{} Transaction {} wouldn't be mined as things stand (e.g. the sender can't fund it).",
+                    "[WARN]".yellow(),
+                    txid
+                );
+                return Ok(());
+            };
+            let interval = state
+                .blockchain
+                .rate(window)
+                .map(|rate| rate.average_interval_secs())
+                .filter(|secs| *secs > 0.0);
+            let when = match interval {
+                Some(secs) => format!(" (about {})", format_duration(blocks as f64 * secs)),
+                None => String::new(),
+            };
+            if blocks == 1 {
+                println!("Transaction {} fits in the next block{}.", txid, when);
+            } else {
+                println!("Transaction {} should be mined in {} blocks{}.", txid, blocks, when);
+            }
+        }
+        Commands::ChainSize { blocks } => {
+            let blockchain = &state.blockchain;
+            let serialized = serde_json::to_vec(blockchain)?.len();
+            let per_block = serde_json::to_vec(&blockchain.chain)?.len() / blockchain.chain.len();
+            match config::saved_chain_size()? {
+                Some((path, size)) => println!("On disk:          {} ({})", format_bytes(size), path),
+                None => println!("On disk:          nothing saved yet"),
+            }
+            println!("Serialized:       {} (compact JSON)", format_bytes(serialized as u64));
+            println!("Blocks:           {}", blockchain.chain.len());
+            println!("Average block:    {}", format_bytes(per_block as u64));
+            println!(
+                "{:<18}{}",
+                format!("After {} more:", blocks),
+                format_bytes((serialized + per_block * blocks) as u64)
+            );
+        }
+        Commands::DifficultyHistory => {
+            let history = state.blockchain.difficulty_history();
+            if history.is_empty() {
+                println!("{}", "No difficulty-adjustment interval has completed yet.".italic());
+            } else {
+                let mut table = Table::new();
+                table
+                    .load_preset(UTF8_FULL)
+                    .set_header(vec!["Blocks", "Time Taken", "Expected", "Difficulty"]);
+                for period in &history {
+                    let change = match period.difficulty_after.cmp(&period.difficulty_before) {
+                        std::cmp::Ordering::Greater => "up",
+                        std::cmp::Ordering::Less => "down",
+                        std::cmp::Ordering::Equal => "unchanged",
+                    };
+                    table.add_row(vec![
+                        format!("#{}-#{}", period.start, period.end),
+                        format!("{}s", period.time_taken),
+                        format!("{}s", period.expected_time),
+                        format!("{} -> {} ({})", period.difficulty_before, period.difficulty_after, change),
+                    ]);
+                }
+                println!("Difficulty adjustments:\n{}", table);
+            }
+            let difficulties: Vec<usize> = state.blockchain.chain.iter().map(|b| b.difficulty).collect();
+            println!(
+                "Difficulty by height (#0-#{}): {}",
+                difficulties.len() - 1,
+                display::sparkline(&difficulties, 60)
+            );
+        }
+        Commands::ImportChain { path } => {
+            let incoming = config::load_blockchain_file(&path)?;
+            let report = state
+                .blockchain
+                .replace_chain_within(incoming, state.config.max_reorg_depth)?;
+            state_changed = true;
+            print_import_report(&state.blockchain, &report);
+        }
+        Commands::ImportTxs { path } => {
+            let transactions = config::load_transactions_file(&path)?;
+            let total = transactions.len();
+            let report = state.blockchain.import_transactions(transactions);
+            state_changed = !report.accepted.is_empty();
+            for (txid, reason) in &report.rejected {
+                println!("{} Rejected {}: {}", "[WARN]".yellow(), txid, reason);
+            }
+            println!(
+                "{} Imported {} of {} transactions into the mempool.",
+                "[SUCCESS]".green(),
+                report.accepted.len(),
+                total
+            );
+        }
+        Commands::SimulateAttack { kind } => {
+            state_changed = false;
+            if !state.blockchain.is_chain_valid() {
+                bail!("Your chain already fails validation, so an attack on it wouldn't show anything. Run `validate` first.");
+            }
+            let attack = match kind {
+                AttackKind::TamperAmount => attack::tamper_amount(&state.blockchain)?,
+                AttackKind::DoubleSpend => attack::double_spend(&state.blockchain)?,
+                AttackKind::ForgeSignature => attack::forge_signature(&state.blockchain)?,
+            };
+            println!("[INFO] Staged the attack on a copy of the chain. Your chain isn't touched.");
+            println!("Before: {}", attack.before);
+            println!("After:  {}", attack.after);
+            if attack.chain.is_chain_valid() {
+                println!(
+                    "{} Validation accepted the altered chain. The attack on block #{} went undetected!",
+                    "[WARN]".yellow(),
+                    attack.height
+                );
+            } else {
+                println!(
+                    "{} Validation rejects the altered chain: block #{} doesn't hold up.",
+                    "[INVALID]".red(),
+                    attack.height
+                );
+            }
+        }
+        Commands::PruneMempool => {
+            let before = state.blockchain.mempool.len();
+            let dropped = state.blockchain.prune_mempool();
+            state_changed = !dropped.is_empty();
+            for (txid, reason) in &dropped {
+                println!("{} Dropped {}: {}", "[WARN]".yellow(), txid, reason);
+            }
+            println!(
+                "{} Kept {} of {} pending transactions.",
+                "[SUCCESS]".green(),
+                before - dropped.len(),
+                before
+            );
+        }
+        #[cfg(feature = "sqlite")]
+        Commands::ExportSqlite { path } => {
+            if config::is_dry_run() {
+                println!("[DRY RUN] Would write {}.", path.display());
+                return Ok(());
+            }
+            let export = mini_blockchain::sqlite::export_chain(&state.blockchain, &path)?;
+            let transactions: usize = state.blockchain.chain.iter().map(|block| block.transactions.len()).sum();
+            println!(
+                "{} Wrote {} blocks and {} transactions to {}.",
+                "[SUCCESS]".green(),
+                export.blocks,
+                export.transactions,
+                path.display()
+            );
+            if export.blocks != state.blockchain.chain.len() as u64 || export.transactions != transactions as u64 {
+                bail!(
+                    "The database doesn't match the chain: expected {} blocks and {} transactions.",
+                    state.blockchain.chain.len(),
+                    transactions
+                );
+            }
+        }
+        Commands::ExportSigned { path } => {
+            let active_wallet_name = state.config.active_wallet.clone().context(
+                "You need an active wallet to sign the export. Use `wallet use <name>` to set one.",
+            )?;
+            let wallet = config::load_wallet(&active_wallet_name)?;
+            let chain = config::load_saved_blockchain()?.context("There's no saved chain to export yet.")?;
+            let bundle = SignedChain::sign(chain, &wallet)?;
+            config::write_user_json(&path, &bundle)?;
+            println!(
+                "{} Wrote the chain to {}, signed by {}.",
+                "[SUCCESS]".green(),
+                path.display(),
+                bundle.signer.to_hex()
+            );
+        }
+        Commands::ImportSigned { path, from } => {
+            let expected = config::resolve_address(&state, &from)?;
+            let data = std::fs::read_to_string(&path)?;
+            let bundle: SignedChain = serde_json::from_str(&data)
+                .context("That file isn't a signed chain bundle.")?;
+            let incoming = bundle.open(&expected)?;
+            println!("{} Signature by {} checks out.", "[VALID]".green(), expected.to_hex());
+            let report = state
+                .blockchain
+                .replace_chain_within(incoming, state.config.max_reorg_depth)?;
+            state_changed = true;
+            print_import_report(&state.blockchain, &report);
+        }
+        Commands::Doctor { .. } => unreachable!("doctor runs before the state is loaded"),
         Commands::Clear => {
-            println!("{}", "This will delete ALL your data (wallets, contacts, blockchain). Are you sure? (y/n)".red().bold());
-            let mut input = String::new();
-            std::io::stdin().read_line(&mut input)?;
-            if input.trim().eq_ignore_ascii_case("y") {
+            let prompt = format!(
+                "This will delete ALL your data on the '{}' network (wallets, contacts, blockchain). Are you sure? (y/n)",
+                config::current_network()
+            );
+            if prompt_yes_no(&prompt.red().bold().to_string())? {
                 config::clear_all_data()?;
                 println!(
-                    "{} All blockchain and wallet data has been wiped clean.",
-                    "[SUCCESS]".green()
+                    "{} All blockchain and wallet data on the '{}' network has been wiped clean.",
+                    "[SUCCESS]".green(),
+                    config::current_network()
                 );
             } else {
                 println!("Operation cancelled.");
@@ -236,9 +1977,430 @@ fn main() -> Result<()> {
         }
     }
 
-    if state_changed {
+    if let Some(before) = before {
+        if state_changed {
+            before.report_changes(&state);
+        }
+        println!("[DRY RUN] Nothing was saved.");
+    } else if state_changed {
         config::save_app_state(&state)?;
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Runs `Blockchain::validate` and reports the outcome, failing with
+/// `BlockchainError::ChainInvalid` (exit code 4) if the chain is invalid.
+fn validate_chain(blockchain: &mut Blockchain, full: bool) -> Result<()> {
+    match blockchain.validate(full) {
+        Some(start) => {
+            println!(
+                "{} The blockchain is valid and its integrity is intact!",
+                "[VALID]".green()
+            );
+            if start > 1 {
+                println!(
+                    "   Re-checked the transactions of blocks #{} onwards; earlier ones passed before. Use --full to re-check everything.",
+                    start
+                );
+            }
+            Ok(())
+        }
+        None => {
+            println!(
+                "{} DANGER: The blockchain has been tampered with or is corrupted!",
+                "[INVALID]".red()
+            );
+            bail!(BlockchainError::ChainInvalid);
+        }
+    }
+}
+
+fn run_doctor(fix: bool) -> Result<()> {
+    let issues = config::diagnose()?;
+    if issues.is_empty() {
+        println!("{} No problems found in the data directory.", "[VALID]".green());
+        return Ok(());
+    }
+    let mut remaining = 0;
+    for issue in &issues {
+        if fix && config::fix_issue(issue)? {
+            if config::is_dry_run() {
+                println!("[DRY RUN] Would fix: {}", issue);
+            } else {
+                println!("{} Fixed: {}", "[SUCCESS]".green(), issue);
+            }
+        } else {
+            remaining += 1;
+            println!("{} {}", "[WARN]".yellow(), issue);
+        }
+    }
+    if remaining > 0 {
+        if !fix && issues.iter().any(|issue| issue.is_fixable()) {
+            println!("[INFO] Run `doctor --fix` to clean up leftover temp files and rebuild damaged wallets.");
+        }
+        bail!("{} problem(s) in the data directory need attention.", remaining);
+    }
+    Ok(())
+}
+
+/// Enough of the app state to tell, after a `--dry-run` command, what it changed.
+struct Snapshot {
+    chain_id: String,
+    blocks: usize,
+    mempool: Vec<String>,
+    contacts: BTreeMap<String, String>,
+    accounts: String,
+    labels: BTreeMap<String, String>,
+    config: String,
+}
+
+impl Snapshot {
+    fn of(state: &config::AppState) -> Self {
+        Snapshot {
+            chain_id: state.blockchain.chain_id().to_string(),
+            blocks: state.blockchain.chain.len(),
+            mempool: state.blockchain.mempool.iter().map(Transaction::txid).collect(),
+            contacts: state.contacts.clone(),
+            accounts: serde_json::to_string(&state.accounts).unwrap_or_default(),
+            labels: state.labels.clone(),
+            config: serde_json::to_string(&state.config).unwrap_or_default(),
+        }
+    }
+
+    fn report_changes(&self, state: &config::AppState) {
+        let after = Snapshot::of(state);
+        if after.chain_id != self.chain_id {
+            println!("[DRY RUN] Would replace the chain with {} ({} blocks).", after.chain_id, after.blocks);
+        } else {
+            for block in state.blockchain.chain.iter().skip(self.blocks) {
+                println!("[DRY RUN] Would add block #{} with hash {}.", block.index, block.hash);
+            }
+        }
+        for txid in after.mempool.iter().filter(|txid| !self.mempool.contains(txid)) {
+            println!("[DRY RUN] Would add transaction {} to the mempool.", txid);
+        }
+        for txid in self.mempool.iter().filter(|txid| !after.mempool.contains(txid)) {
+            match state.blockchain.find_transaction(txid) {
+                Some((TxLocation::Block(height), _)) => {
+                    println!("[DRY RUN] Would confirm transaction {} in block #{}.", txid, height)
+                }
+                _ => println!("[DRY RUN] Would remove transaction {} from the mempool.", txid),
+            }
+        }
+        for (name, address) in &after.contacts {
+            match self.contacts.get(name) {
+                None => println!("[DRY RUN] Would add contact '{}'.", name),
+                Some(previous) if previous != address => println!("[DRY RUN] Would change contact '{}'.", name),
+                _ => {}
+            }
+        }
+        for name in self.contacts.keys().filter(|name| !after.contacts.contains_key(*name)) {
+            println!("[DRY RUN] Would remove contact '{}'.", name);
+        }
+        if after.accounts != self.accounts {
+            println!("[DRY RUN] Would change the accounts to {}.", after.accounts);
+        }
+        for (address, name) in &after.labels {
+            if self.labels.get(address) != Some(name) {
+                println!("[DRY RUN] Would label {} as '{}'.", short(address), name);
+            }
+        }
+        for address in self.labels.keys().filter(|address| !after.labels.contains_key(*address)) {
+            println!("[DRY RUN] Would remove the label for {}.", short(address));
+        }
+        if after.config != self.config {
+            println!("[DRY RUN] Would change the settings to {}.", after.config);
+        }
+    }
+}
+
+/// The height `add-tx --at-height`/`--in-blocks` locks a transaction until,
+/// given the current tip.
+fn resolve_locktime(tip: u64, at_height: Option<u64>, in_blocks: Option<u64>) -> Result<Option<u64>> {
+    match (at_height, in_blocks) {
+        (Some(height), _) if height <= tip => bail!(BlockchainError::InvalidInput(format!(
+            "Block #{} has already been mined (current tip {}). Pick a later height.",
+            height, tip
+        ))),
+        (Some(height), _) => Ok(Some(height)),
+        (None, Some(blocks)) => match tip.checked_add(blocks) {
+            Some(height) => Ok(Some(height)),
+            None => bail!(BlockchainError::InvalidInput(format!(
+                "--in-blocks {} is too far past the current tip {}.",
+                blocks, tip
+            ))),
+        },
+        (None, None) => Ok(None),
+    }
+}
+
+/// When the reward `coinbase` from the block at `height` can be spent.
+fn reward_maturity(coinbase: &Transaction, height: u64) -> String {
+    match coinbase.condition {
+        Some(SpendCondition::Timelock { height: unlock }) if unlock > height + 1 => {
+            let blocks = unlock - height - 1;
+            format!(
+                "It matures at block #{}, {} block{} from now.",
+                unlock,
+                blocks,
+                if blocks == 1 { "" } else { "s" }
+            )
+        }
+        _ => "It can be spent right away.".to_string(),
+    }
+}
+
+/// Makes `name` the active wallet, failing with `WalletNotFound` if
+/// `storage` has no wallet by that name.
+fn use_wallet(storage: &dyn Storage, config: &mut Config, name: &str) -> Result<()> {
+    if !config::wallet_exists_in(storage, name)? {
+        bail!(BlockchainError::WalletNotFound(name.to_string()));
+    }
+    config.active_wallet = Some(name.to_string());
+    Ok(())
+}
+
+/// Queues a transaction moving everything `wallet` can spend now to
+/// `destination`. Coins still held by a spending condition stay behind.
+/// Returns the amount swept.
+fn sweep(blockchain: &mut Blockchain, wallet: &Wallet, destination: &PublicKey) -> Result<u64> {
+    let source = PublicKey(wallet.public_key);
+    let spendable = blockchain.get_balance(&source)? - blockchain.locked_balance(&source) as i64;
+    if spendable <= 0 {
+        return Ok(0);
+    }
+    let tx = Transaction::builder(destination.clone(), spendable as u64)
+        .network(&blockchain.network)
+        .nonce(blockchain.next_nonce(&source))
+        .sign(wallet);
+    blockchain.add_transaction(tx)?;
+    Ok(spendable as u64)
+}
+
+fn prompt_yes_no(prompt: &str) -> Result<bool> {
+    println!("{}", prompt);
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+    Ok(input.trim().eq_ignore_ascii_case("y"))
+}
+
+/// Formats a byte count with a binary unit, e.g. `1.5 KiB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Formats a number of seconds for people, e.g. `45s`, `3.5 min` or `2.0 h`.
+fn format_duration(secs: f64) -> String {
+    if secs < 60.0 {
+        format!("{:.0}s", secs)
+    } else if secs < 3600.0 {
+        format!("{:.1} min", secs / 60.0)
+    } else {
+        format!("{:.1} h", secs / 3600.0)
+    }
+}
+
+/// Asks a question and returns the first letter of the answer, lowercased.
+fn prompt_choice(prompt: &str) -> Result<char> {
+    println!("{}", prompt);
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+    Ok(input.trim().chars().next().unwrap_or(' ').to_ascii_lowercase())
+}
+
+fn print_import_report(blockchain: &Blockchain, report: &ReorgReport) {
+    println!(
+        "{} Imported the chain. It now has {} blocks.",
+        "[SUCCESS]".green(),
+        blockchain.chain.len()
+    );
+    if !report.reinjected.is_empty() || !report.dropped.is_empty() {
+        println!(
+            "[INFO] Reorganized from block #{}: {} orphaned transactions returned to the mempool.",
+            report.fork_height,
+            report.reinjected.len()
+        );
+    }
+    for (txid, reason) in &report.dropped {
+        println!("{} Dropped orphaned transaction {}: {}", "[WARN]".yellow(), txid, reason);
+    }
+}
+
+fn print_tx_info(blockchain: &Blockchain, tx: &Transaction) {
+    let txid = tx.txid();
+    let sender = tx
+        .source
+        .as_ref()
+        .map(|s| s.to_hex())
+        .unwrap_or_else(|| "COINBASE (Mining Reward)".to_string());
+    let signature = match (&tx.source, &tx.signature) {
+        (None, None) => "none (coinbase)".normal(),
+        _ if tx.is_valid() => "valid".green(),
+        _ => "INVALID".red(),
+    };
+    let status = match blockchain.find_transaction(&txid) {
+        Some((TxLocation::Block(height), _)) => format!(
+            "confirmed in block #{} ({} confirmations)",
+            height,
+            blockchain.confirmations(height)
+        ),
+        Some((TxLocation::Mempool, _)) => "pending in the mempool".to_string(),
+        None => "not on this chain".to_string(),
+    };
+
+    println!("Transaction {}", txid.bold());
+    println!("  Sender:    {}", sender);
+    println!("  Recipient: {}", tx.destination.to_hex());
+    println!("  Amount:    {}", tx.amount);
+    if let Some(condition) = &tx.condition {
+        println!("  Condition: {}", condition);
+    }
+    if let Some(locktime) = tx.locktime {
+        println!("  Minable:   from block #{}", locktime);
+    }
+    println!("  Signature: {}", signature);
+    println!("  Status:    {}", status);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_error_kind_has_its_own_exit_code() {
+        let cases = [
+            (BlockchainError::InvalidInput("bad".to_string()), ExitStatus::BadInput, 2),
+            (
+                BlockchainError::InsufficientFunds {
+                    available: 1,
+                    requested: 2,
+                },
+                ExitStatus::InsufficientFunds,
+                3,
+            ),
+            (BlockchainError::ChainInvalid, ExitStatus::ChainInvalid, 4),
+            (BlockchainError::WalletNotFound("alice".to_string()), ExitStatus::WalletNotFound, 5),
+        ];
+        for (error, status, code) in cases {
+            assert_eq!(ExitStatus::from_error(&anyhow::Error::new(error.clone())), status);
+            // Context added on the way up doesn't hide the kind.
+            let wrapped = anyhow::Error::new(error).context("while running the command");
+            assert_eq!(ExitStatus::from_error(&wrapped), status);
+            assert_eq!(status as u8, code);
+        }
+        // Attached as context to a lower-level error, as `PublicKey::from_hex` does.
+        let err = PublicKey::from_hex("not hex").unwrap_err();
+        assert_eq!(ExitStatus::from_error(&err), ExitStatus::BadInput);
+    }
+
+    #[test]
+    fn other_errors_exit_with_failure() {
+        let err = anyhow::anyhow!("disk full");
+        assert_eq!(ExitStatus::from_error(&err), ExitStatus::Failure);
+        assert_eq!(ExitStatus::Failure as u8, 1);
+        assert_eq!(ExitStatus::Success as u8, 0);
+    }
+
+    #[test]
+    fn locktime_from_height_or_block_count() {
+        assert_eq!(resolve_locktime(5, Some(8), None).unwrap(), Some(8));
+        assert_eq!(resolve_locktime(5, None, Some(3)).unwrap(), Some(8));
+        assert_eq!(resolve_locktime(5, None, None).unwrap(), None);
+    }
+
+    #[test]
+    fn locktime_rejects_mined_heights_and_overflow() {
+        for err in [
+            resolve_locktime(5, Some(5), None).unwrap_err(),
+            resolve_locktime(5, None, Some(u64::MAX)).unwrap_err(),
+        ] {
+            assert_eq!(ExitStatus::from_error(&err) as u8, ExitStatus::BadInput as u8);
+        }
+    }
+
+    #[test]
+    fn validating_a_tampered_chain_exits_with_chain_invalid() {
+        let (mut chain, _) = mini_blockchain::testkit::mined_chain(3, 0);
+        assert!(validate_chain(&mut chain, false).is_ok());
+
+        chain.chain[2].transactions[0].amount += 1;
+        let err = validate_chain(&mut chain, false).unwrap_err();
+        assert_eq!(ExitStatus::from_error(&err), ExitStatus::ChainInvalid);
+        assert_eq!(ExitStatus::from_error(&validate_chain(&mut chain, true).unwrap_err()), ExitStatus::ChainInvalid);
+    }
+
+    #[test]
+    fn wallet_use_and_unset_change_the_saved_active_wallet() {
+        use mini_blockchain::{storage::MemoryStorage, testkit::seeded_wallet};
+        let storage = MemoryStorage::new();
+        config::save_wallet_in(&storage, "alice", &seeded_wallet(1)).unwrap();
+        let mut state = config::load_app_state_in(&storage).unwrap();
+
+        use_wallet(&storage, &mut state.config, "alice").unwrap();
+        config::save_app_state_in(&storage, &state).unwrap();
+        let mut state = config::load_app_state_in(&storage).unwrap();
+        assert_eq!(state.config.active_wallet.as_deref(), Some("alice"));
+
+        let err = use_wallet(&storage, &mut state.config, "bob").unwrap_err();
+        assert_eq!(ExitStatus::from_error(&err), ExitStatus::WalletNotFound);
+        assert_eq!(state.config.active_wallet.as_deref(), Some("alice"));
+
+        // What `wallet unset` does.
+        assert_eq!(state.config.active_wallet.take().as_deref(), Some("alice"));
+        config::save_app_state_in(&storage, &state).unwrap();
+        assert!(config::load_app_state_in(&storage).unwrap().config.active_wallet.is_none());
+    }
+
+    #[test]
+    fn sweep_moves_the_unlocked_balance_to_the_new_key() {
+        use mini_blockchain::testkit::{funded_wallet, seeded_wallet, signed_tx};
+        let (mut chain, miner) = funded_wallet(100);
+        let owner = seeded_wallet(1);
+        let owner_address = PublicKey(owner.public_key);
+        let locked = Transaction::builder(owner_address.clone(), 30)
+            .condition(SpendCondition::Timelock { height: 100 })
+            .network(&chain.network)
+            .nonce(chain.next_nonce(&PublicKey(miner.public_key)))
+            .sign(&miner);
+        chain.add_transaction(locked).unwrap();
+        chain.add_transaction(signed_tx(&chain, &miner, &owner, 20)).unwrap();
+        chain.mine_pending_transactions(PublicKey(miner.public_key)).unwrap();
+
+        let new_address = PublicKey(seeded_wallet(2).public_key);
+        assert_eq!(sweep(&mut chain, &owner, &new_address).unwrap(), 20);
+        chain.mine_pending_transactions(PublicKey(miner.public_key)).unwrap();
+        assert_eq!(chain.get_balance(&new_address).unwrap(), 20);
+        assert_eq!(chain.get_balance(&owner_address).unwrap(), 30);
+        assert_eq!(chain.locked_balance(&owner_address), 30);
+        assert_eq!(sweep(&mut chain, &owner, &new_address).unwrap(), 0);
+        assert!(chain.is_chain_valid());
+    }
+
+    #[test]
+    fn mine_reports_the_reward_maturity_countdown() {
+        let (mut chain, miner) = mini_blockchain::testkit::mined_chain(2, 0);
+        let reward = |chain: &Blockchain| chain.chain.last().unwrap().transactions[0].clone();
+        assert_eq!(reward_maturity(&reward(&chain), 2), "It can be spent right away.");
+
+        chain.coinbase_maturity = 3;
+        chain.mine_pending_transactions(PublicKey(miner.public_key)).unwrap();
+        assert_eq!(reward_maturity(&reward(&chain), 3), "It matures at block #6, 2 blocks from now.");
+        chain.coinbase_maturity = 2;
+        chain.mine_pending_transactions(PublicKey(miner.public_key)).unwrap();
+        assert_eq!(reward_maturity(&reward(&chain), 4), "It matures at block #6, 1 block from now.");
+        chain.coinbase_maturity = 1;
+        chain.mine_pending_transactions(PublicKey(miner.public_key)).unwrap();
+        assert_eq!(reward_maturity(&reward(&chain), 5), "It can be spent right away.");
+    }
+}
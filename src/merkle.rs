@@ -0,0 +1,129 @@
+//! SHA-256 Merkle trees over transaction ids, so a single transaction's
+//! inclusion in a block can be proven without the rest of the block.
+
+use sha2::{Digest, Sha256};
+
+/// Root of a block with no transactions.
+pub const EMPTY_ROOT: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Hex SHA-256 of two nodes' hex hashes, left then right.
+fn hash_pair(left: &str, right: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    format!("{:x}", hasher.finalize())
+}
+
+/// The next level up: pairs of nodes hashed together, with the last node
+/// paired with itself when the count is odd.
+fn parent_level(level: &[String]) -> Vec<String> {
+    level
+        .chunks(2)
+        .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+/// Root of the tree whose leaves are `txids`, in order.
+pub fn merkle_root(txids: &[String]) -> String {
+    if txids.is_empty() {
+        return EMPTY_ROOT.to_string();
+    }
+    let mut level = txids.to_vec();
+    while level.len() > 1 {
+        level = parent_level(&level);
+    }
+    level.remove(0)
+}
+
+/// Sibling hashes from the leaf at `index` up to the root, or `None` if
+/// there is no such leaf.
+pub fn merkle_proof(txids: &[String], index: usize) -> Option<Vec<String>> {
+    if index >= txids.len() {
+        return None;
+    }
+    let mut proof = Vec::new();
+    let mut level = txids.to_vec();
+    let mut position = index;
+    while level.len() > 1 {
+        let sibling = level.get(position ^ 1).unwrap_or(&level[position]);
+        proof.push(sibling.clone());
+        level = parent_level(&level);
+        position /= 2;
+    }
+    Some(proof)
+}
+
+/// Whether `proof` shows that `txid` is leaf `index` of the tree with `root`.
+/// The bits of `index` say, level by level, which side the sibling is on.
+/// Because an odd last node is paired with itself, the last leaf of such a
+/// level also verifies at the (nonexistent) index after it.
+pub fn verify_merkle_proof(txid: &str, index: usize, proof: &[String], root: &str) -> bool {
+    let side = |level: usize| index.checked_shr(level as u32).unwrap_or(0);
+    if side(proof.len()) != 0 {
+        return false;
+    }
+    let computed = proof.iter().enumerate().fold(txid.to_string(), |node, (level, sibling)| {
+        if side(level) & 1 == 0 {
+            hash_pair(&node, sibling)
+        } else {
+            hash_pair(sibling, &node)
+        }
+    });
+    computed == root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("{:x}", Sha256::digest(i.to_le_bytes()))).collect()
+    }
+
+    #[test]
+    fn root_of_small_trees() {
+        let [a, b, c] = <[String; 3]>::try_from(leaves(3)).unwrap();
+        assert_eq!(merkle_root(&[]), EMPTY_ROOT);
+        assert_eq!(merkle_root(std::slice::from_ref(&a)), a);
+        assert_eq!(merkle_root(&[a.clone(), b.clone()]), hash_pair(&a, &b));
+        assert_eq!(
+            merkle_root(&[a.clone(), b.clone(), c.clone()]),
+            hash_pair(&hash_pair(&a, &b), &hash_pair(&c, &c))
+        );
+    }
+
+    #[test]
+    fn every_leaf_has_a_verifying_proof() {
+        for count in 1..=9 {
+            let txids = leaves(count);
+            let root = merkle_root(&txids);
+            for (index, txid) in txids.iter().enumerate() {
+                let proof = merkle_proof(&txids, index).unwrap();
+                assert!(verify_merkle_proof(txid, index, &proof, &root), "leaf {} of {}", index, count);
+            }
+            assert_eq!(merkle_proof(&txids, count), None);
+        }
+    }
+
+    #[test]
+    fn altered_proofs_dont_verify() {
+        let txids = leaves(5);
+        let root = merkle_root(&txids);
+        let proof = merkle_proof(&txids, 2).unwrap();
+        assert!(!verify_merkle_proof(&txids[3], 2, &proof, &root));
+        assert!(!verify_merkle_proof(&txids[2], 3, &proof, &root));
+        assert!(!verify_merkle_proof(&txids[2], 2, &proof[1..], &root));
+        let mut swapped = proof.clone();
+        swapped[0] = txids[4].clone();
+        assert!(!verify_merkle_proof(&txids[2], 2, &swapped, &root));
+    }
+
+    #[test]
+    fn repeating_the_last_leaf_keeps_the_root() {
+        // Why blocks may not list a transaction twice (CVE-2012-2459).
+        let txids = leaves(3);
+        let mut mutated = txids.clone();
+        mutated.push(txids[2].clone());
+        assert_eq!(merkle_root(&txids), merkle_root(&mutated));
+    }
+}
@@ -0,0 +1,199 @@
+use crate::block::{Block, BlockHeader};
+use crate::blockchain::{Blockchain, TxLocation};
+use crate::merkle;
+use crate::transaction::Transaction;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Self-contained proof that a transaction was mined: the transaction and
+/// what ties it to a mined block. Anyone can check it without the rest of
+/// the chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentReceipt {
+    pub chain_id: String,
+    pub txid: String,
+    pub transaction: Transaction,
+    pub inclusion: Inclusion,
+}
+
+/// How a receipt shows its transaction is in a block.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Inclusion {
+    /// The block's header and the Merkle path from the transaction to its root.
+    MerkleProof {
+        header: BlockHeader,
+        tx_index: usize,
+        proof: Vec<String>,
+    },
+    /// The whole block, for version 1 blocks, which have no Merkle root.
+    FullBlock { block: Block },
+}
+
+impl PaymentReceipt {
+    pub fn new(blockchain: &Blockchain, txid: &str) -> Result<Self> {
+        let (height, transaction) = match blockchain.find_transaction(txid) {
+            Some((TxLocation::Block(height), tx)) => (height, tx.clone()),
+            Some((TxLocation::Mempool, _)) => {
+                bail!("Transaction {} hasn't been mined yet, so there's nothing to prove.", txid)
+            }
+            None => bail!("No transaction with id {} on the chain.", txid),
+        };
+        let block = &blockchain.chain[height as usize];
+        let inclusion = if block.version >= 2 {
+            let tx_index = block
+                .transactions
+                .iter()
+                .position(|tx| tx.txid() == txid)
+                .context("The transaction isn't in the block it was found in.")?;
+            Inclusion::MerkleProof {
+                header: block.header(),
+                tx_index,
+                proof: block.merkle_proof(tx_index).unwrap_or_default(),
+            }
+        } else {
+            Inclusion::FullBlock { block: block.clone() }
+        };
+        Ok(PaymentReceipt {
+            chain_id: blockchain.chain_id().to_string(),
+            txid: txid.to_string(),
+            transaction,
+            inclusion,
+        })
+    }
+
+    /// Checks that the transaction is correctly signed and has the receipt's
+    /// id, and that it is in a properly mined block.
+    pub fn verify(&self) -> Result<&Transaction> {
+        if self.transaction.txid() != self.txid {
+            bail!("The transaction doesn't have the receipt's id. The receipt was altered.");
+        }
+        if !self.transaction.is_valid() {
+            bail!("The transaction's signature is invalid.");
+        }
+        match &self.inclusion {
+            Inclusion::MerkleProof { header, tx_index, proof } => {
+                if !header.verify_hash() {
+                    bail!("The block header doesn't match its hash. The receipt was altered.");
+                }
+                if !header.has_valid_pow() {
+                    bail!("The block doesn't carry valid proof-of-work.");
+                }
+                if !merkle::verify_merkle_proof(&self.txid, *tx_index, proof, &header.merkle_root) {
+                    bail!("The Merkle proof doesn't lead to the block's root. The receipt was altered.");
+                }
+            }
+            Inclusion::FullBlock { block } => {
+                if !block.verify_hash() {
+                    bail!("The block's contents don't match its hash. The receipt was altered.");
+                }
+                if !block.has_valid_pow() {
+                    bail!("The block doesn't carry valid proof-of-work.");
+                }
+                if !block.transactions.contains(&self.transaction) {
+                    bail!("The block doesn't contain the transaction this receipt is for.");
+                }
+            }
+        }
+        Ok(&self.transaction)
+    }
+
+    /// Height of the block the transaction is in.
+    pub fn block_index(&self) -> u64 {
+        match &self.inclusion {
+            Inclusion::MerkleProof { header, .. } => header.index,
+            Inclusion::FullBlock { block } => block.index,
+        }
+    }
+
+    fn block_hash(&self) -> &str {
+        match &self.inclusion {
+            Inclusion::MerkleProof { header, .. } => &header.hash,
+            Inclusion::FullBlock { block } => &block.hash,
+        }
+    }
+
+    /// Whether the receipt's block is part of `blockchain`.
+    pub fn is_on_chain(&self, blockchain: &Blockchain) -> bool {
+        blockchain.chain_id() == self.chain_id
+            && blockchain
+                .chain
+                .get(self.block_index() as usize)
+                .is_some_and(|block| block.hash == self.block_hash())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::{funded_wallet, seeded_wallet, signed_tx};
+    use crate::transaction::PublicKey;
+
+    /// A chain whose tip holds a payment, and that payment's id.
+    fn paid_chain() -> (Blockchain, String) {
+        let (mut chain, payer) = funded_wallet(50);
+        for amount in [10, 20] {
+            chain.add_transaction(signed_tx(&chain, &payer, &seeded_wallet(1), amount)).unwrap();
+        }
+        chain.mine_pending_transactions(PublicKey(payer.public_key)).unwrap();
+        let txid = chain.chain.last().unwrap().transactions[2].txid();
+        (chain, txid)
+    }
+
+    #[test]
+    fn receipt_proves_a_mined_payment() {
+        let (chain, txid) = paid_chain();
+        let receipt = PaymentReceipt::new(&chain, &txid).unwrap();
+        assert!(matches!(receipt.inclusion, Inclusion::MerkleProof { tx_index: 2, .. }));
+        assert_eq!(receipt.verify().unwrap().amount, 20);
+        assert!(receipt.is_on_chain(&chain));
+
+        let json = serde_json::to_string(&receipt).unwrap();
+        let decoded: PaymentReceipt = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.verify().unwrap().txid(), txid);
+    }
+
+    #[test]
+    fn altered_receipts_are_refused() {
+        let (chain, txid) = paid_chain();
+        let receipt = PaymentReceipt::new(&chain, &txid).unwrap();
+
+        let mut amount = receipt.clone();
+        amount.transaction.amount = 2000;
+        assert!(amount.verify().is_err());
+
+        let mut index = receipt.clone();
+        if let Inclusion::MerkleProof { tx_index, .. } = &mut index.inclusion {
+            *tx_index = 1;
+        }
+        assert!(index.verify().is_err());
+
+        let mut header = receipt.clone();
+        if let Inclusion::MerkleProof { header, .. } = &mut header.inclusion {
+            header.timestamp += 1;
+        }
+        assert!(header.verify().is_err());
+    }
+
+    #[test]
+    fn version_1_blocks_are_proven_whole() {
+        let (mut chain, txid) = paid_chain();
+        let tip = chain.chain.last_mut().unwrap();
+        tip.version = 1;
+        tip.merkle_root.clear();
+        tip.nonce = 0;
+        tip.mine();
+        let receipt = PaymentReceipt::new(&chain, &txid).unwrap();
+        assert!(matches!(receipt.inclusion, Inclusion::FullBlock { .. }));
+        assert!(receipt.verify().is_ok());
+    }
+
+    #[test]
+    fn unmined_transactions_have_no_receipt() {
+        let (mut chain, payer) = funded_wallet(50);
+        let tx = signed_tx(&chain, &payer, &seeded_wallet(1), 10);
+        chain.add_transaction(tx.clone()).unwrap();
+        assert!(PaymentReceipt::new(&chain, &tx.txid()).is_err());
+        assert!(PaymentReceipt::new(&chain, "nope").is_err());
+    }
+}
@@ -0,0 +1,95 @@
+//! Export of the chain to a SQLite database for ad-hoc SQL queries. Only
+//! built with the `sqlite` feature.
+
+use crate::blockchain::Blockchain;
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+const SCHEMA: &str = "
+    DROP TABLE IF EXISTS transactions;
+    DROP TABLE IF EXISTS blocks;
+    CREATE TABLE blocks (
+        height INTEGER PRIMARY KEY,
+        hash TEXT NOT NULL UNIQUE,
+        previous_hash TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        nonce INTEGER NOT NULL,
+        difficulty INTEGER NOT NULL,
+        version INTEGER NOT NULL
+    );
+    CREATE TABLE transactions (
+        txid TEXT NOT NULL,
+        block_height INTEGER NOT NULL REFERENCES blocks(height),
+        position INTEGER NOT NULL,
+        source TEXT,
+        destination TEXT NOT NULL,
+        amount INTEGER NOT NULL,
+        condition TEXT,
+        locktime INTEGER,
+        PRIMARY KEY (block_height, position)
+    );
+    CREATE INDEX transactions_source ON transactions(source);
+    CREATE INDEX transactions_destination ON transactions(destination);
+";
+
+/// Row counts of a finished export, read back from the database.
+#[derive(Debug, Clone, Copy)]
+pub struct SqliteExport {
+    pub blocks: u64,
+    pub transactions: u64,
+}
+
+/// Writes every block and confirmed transaction to the database at `path`,
+/// replacing the `blocks` and `transactions` tables if they already exist.
+/// Coinbase transactions have a NULL `source`.
+pub fn export_chain(blockchain: &Blockchain, path: &Path) -> Result<SqliteExport> {
+    let mut conn = Connection::open(path)
+        .with_context(|| format!("Couldn't open {} as a SQLite database.", path.display()))?;
+    let db = conn.transaction()?;
+    db.execute_batch(SCHEMA)?;
+    {
+        let mut insert_block = db.prepare(
+            "INSERT INTO blocks (height, hash, previous_hash, timestamp, nonce, difficulty, version)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+        let mut insert_tx = db.prepare(
+            "INSERT INTO transactions
+                 (txid, block_height, position, source, destination, amount, condition, locktime)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
+        for block in &blockchain.chain {
+            insert_block.execute(params![
+                block.index as i64,
+                block.hash,
+                block.previous_hash,
+                block.timestamp,
+                block.nonce as i64,
+                block.difficulty as i64,
+                block.version,
+            ])?;
+            for (position, tx) in block.transactions.iter().enumerate() {
+                insert_tx.execute(params![
+                    tx.txid(),
+                    block.index as i64,
+                    position as i64,
+                    tx.source.as_ref().map(|source| source.to_hex()),
+                    tx.destination.to_hex(),
+                    tx.amount as i64,
+                    tx.condition.map(|condition| condition.to_string()),
+                    tx.locktime.map(|locktime| locktime as i64),
+                ])?;
+            }
+        }
+    }
+    let count = |table: &str| -> Result<u64> {
+        let rows: i64 = db.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))?;
+        Ok(rows as u64)
+    };
+    let export = SqliteExport {
+        blocks: count("blocks")?,
+        transactions: count("transactions")?,
+    };
+    db.commit()?;
+    Ok(export)
+}
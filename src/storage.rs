@@ -0,0 +1,320 @@
+use anyhow::Result;
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::Duration,
+};
+
+/// Filesystem operations that fail transiently are attempted this many times.
+const IO_ATTEMPTS: u32 = 3;
+const IO_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Where the app keeps its documents (settings, chain, contacts, wallets),
+/// each addressed by a relative key such as `chain.json` or `wallets/alice.json`.
+/// `config::load_app_state_in` and friends work with any implementation.
+pub trait Storage {
+    /// The document stored under `key`, or `None` if there isn't one.
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    /// Replaces the document under `key` with whatever `produce` writes, so
+    /// large documents can be streamed rather than built in memory first.
+    fn write(&self, key: &str, produce: &mut dyn FnMut(&mut dyn Write) -> io::Result<()>) -> Result<()>;
+    /// Deletes the document under `key`; does nothing if there isn't one.
+    fn remove(&self, key: &str) -> Result<()>;
+    fn exists(&self, key: &str) -> Result<bool>;
+    /// Size in bytes of the document under `key`, or `None` if there isn't one.
+    fn size(&self, key: &str) -> Result<Option<u64>> {
+        Ok(self.read(key)?.map(|data| data.len() as u64))
+    }
+    /// Names of the documents directly inside `dir`, e.g. `alice.json` for `wallets`.
+    fn list(&self, dir: &str) -> Result<Vec<String>>;
+    /// How to refer to a document in messages to the user. Different for
+    /// every document and storage location, so it also keys caches shared
+    /// across `Storage` values.
+    fn describe(&self, key: &str) -> String;
+}
+
+/// Documents as files under a root directory; what the CLI uses. Each write
+/// goes to `<file>.tmp` first and is renamed into place, so an interrupted
+/// save leaves the old file intact (and a stray `.tmp` for `doctor`).
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    root: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FileStorage { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl Storage for FileStorage {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.root.join(key);
+        match with_retry(|| fs::read(&path)) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(describe_io_error(err, "read", &path)),
+        }
+    }
+
+    fn write(&self, key: &str, produce: &mut dyn FnMut(&mut dyn Write) -> io::Result<()>) -> Result<()> {
+        let path = self.root.join(key);
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        with_retry(|| {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut writer = BufWriter::new(File::create(&temp)?);
+            produce(&mut writer)?;
+            writer.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+            fs::rename(&temp, &path)
+        })
+        .map_err(|err| {
+            let _ = fs::remove_file(&temp);
+            describe_io_error(err, "write", &path)
+        })
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        let path = self.root.join(key);
+        match with_retry(|| fs::remove_file(&path)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                Err(describe_io_error(err, "delete", &path))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.root.join(key).is_file())
+    }
+
+    fn size(&self, key: &str) -> Result<Option<u64>> {
+        let path = self.root.join(key);
+        match fs::metadata(&path) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(describe_io_error(err, "read the size of", &path)),
+        }
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>> {
+        let path = self.root.join(dir);
+        let entries = match fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(describe_io_error(err, "list", &path)),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.path().is_file() {
+                if let Some(name) = entry.file_name().to_str() {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn describe(&self, key: &str) -> String {
+        self.root.join(key).display().to_string()
+    }
+}
+
+/// Keeps every document in memory, for tests and embedding without touching disk.
+#[derive(Debug)]
+pub struct MemoryStorage {
+    /// Tells instances apart in `describe`.
+    id: u64,
+    documents: RefCell<BTreeMap<String, Vec<u8>>>,
+}
+
+static NEXT_MEMORY_STORAGE_ID: AtomicU64 = AtomicU64::new(1);
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage {
+            id: NEXT_MEMORY_STORAGE_ID.fetch_add(1, Ordering::Relaxed),
+            documents: RefCell::default(),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.documents.borrow().get(key).cloned())
+    }
+
+    fn write(&self, key: &str, produce: &mut dyn FnMut(&mut dyn Write) -> io::Result<()>) -> Result<()> {
+        let mut data = Vec::new();
+        produce(&mut data)?;
+        self.documents.borrow_mut().insert(key.to_string(), data);
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        self.documents.borrow_mut().remove(key);
+        Ok(())
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.documents.borrow().contains_key(key))
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>> {
+        let dir = dir.trim_end_matches('/');
+        let prefix = if dir.is_empty() { String::new() } else { format!("{}/", dir) };
+        Ok(self
+            .documents
+            .borrow()
+            .keys()
+            .filter_map(|key| key.strip_prefix(&prefix))
+            .filter(|name| !name.contains('/'))
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn describe(&self, key: &str) -> String {
+        format!("memory storage #{}: {}", self.id, key)
+    }
+}
+
+/// Runs `op`, trying again after a short, growing pause if it fails in a way
+/// that usually clears up by itself (interrupted call, file locked by another
+/// process).
+pub(crate) fn with_retry<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(err) if attempt < IO_ATTEMPTS && is_transient(&err) => {
+                thread::sleep(IO_RETRY_DELAY * attempt);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ResourceBusy
+    )
+}
+
+/// Says which kind of failure an IO error was, so "missing" and "not allowed"
+/// don't both come out as a bare OS message.
+pub(crate) fn describe_io_error(err: io::Error, action: &str, path: &Path) -> anyhow::Error {
+    let reason = match err.kind() {
+        ErrorKind::NotFound => "not found",
+        ErrorKind::PermissionDenied => "permission denied",
+        _ if is_transient(&err) => "the file was still busy after several attempts",
+        _ => "unexpected IO error",
+    };
+    let message = format!("Couldn't {} {} ({}).", action, path.display(), reason);
+    anyhow::Error::new(err).context(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh, empty directory under the system temp dir.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mini-blockchain-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn put(storage: &dyn Storage, key: &str, data: &[u8]) -> Result<()> {
+        storage.write(key, &mut |writer| writer.write_all(data))
+    }
+
+    #[test]
+    fn file_write_replaces_the_document_without_leaving_a_temp_file() {
+        let dir = scratch_dir("write");
+        let storage = FileStorage::new(&dir);
+        put(&storage, "wallets/a.json", b"first").unwrap();
+        put(&storage, "wallets/a.json", b"second").unwrap();
+        assert_eq!(storage.read("wallets/a.json").unwrap().as_deref(), Some(&b"second"[..]));
+        assert_eq!(storage.list("wallets").unwrap(), vec!["a.json"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn memory_list_only_returns_direct_children() {
+        let storage = MemoryStorage::new();
+        for key in ["chain.json", "wallets/a.json", "wallets/old/b.json"] {
+            put(&storage, key, b"{}").unwrap();
+        }
+        assert_eq!(storage.list("").unwrap(), vec!["chain.json"]);
+        assert_eq!(storage.list("wallets").unwrap(), vec!["a.json"]);
+        storage.remove("wallets/a.json").unwrap();
+        assert!(storage.list("wallets").unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_file_write_keeps_the_old_document() {
+        let dir = scratch_dir("failed-write");
+        let storage = FileStorage::new(&dir);
+        put(&storage, "chain.json", b"old").unwrap();
+        let result = storage.write("chain.json", &mut |writer| {
+            writer.write_all(b"half")?;
+            Err(io::Error::other("disk full"))
+        });
+        assert!(result.is_err());
+        assert_eq!(storage.read("chain.json").unwrap().as_deref(), Some(&b"old"[..]));
+        assert_eq!(storage.list("").unwrap(), vec!["chain.json"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// An operation that fails with `kind` the first `failures` times, and
+    /// counts how often it was tried.
+    fn flaky(kind: ErrorKind, failures: u32, calls: &mut u32) -> io::Result<&'static str> {
+        *calls += 1;
+        if *calls <= failures {
+            Err(io::Error::from(kind))
+        } else {
+            Ok("done")
+        }
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let mut calls = 0;
+        assert_eq!(with_retry(|| flaky(ErrorKind::Interrupted, 1, &mut calls)).unwrap(), "done");
+        assert_eq!(calls, 2);
+
+        let mut calls = 0;
+        let err = with_retry(|| flaky(ErrorKind::Interrupted, u32::MAX, &mut calls)).unwrap_err();
+        assert_eq!((err.kind(), calls), (ErrorKind::Interrupted, IO_ATTEMPTS));
+    }
+
+    #[test]
+    fn permission_errors_fail_without_retrying() {
+        let mut calls = 0;
+        let err = with_retry(|| flaky(ErrorKind::PermissionDenied, u32::MAX, &mut calls)).unwrap_err();
+        assert_eq!((err.kind(), calls), (ErrorKind::PermissionDenied, 1));
+        let message = describe_io_error(err, "read", Path::new("chain.json")).to_string();
+        assert_eq!(message, "Couldn't read chain.json (permission denied).");
+    }
+}
@@ -0,0 +1,236 @@
+//! Deterministic fixtures for tests: seeded wallets, pre-mined chains and
+//! signed transactions. Enabled for this crate's own tests, and for other
+//! crates through the `testkit` feature.
+
+use crate::block::Block;
+use crate::blockchain::{Blockchain, MINING_REWARD};
+use crate::transaction::{PublicKey, Transaction};
+use crate::wallet::Wallet;
+use anyhow::{bail, Result};
+use p256::ecdsa::{Signature, SigningKey};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+
+/// A wallet whose key is derived from `seed`, so the same seed always gives
+/// the same address.
+pub fn seeded_wallet(seed: u64) -> Wallet {
+    let mut counter = 0u64;
+    loop {
+        let digest = Sha256::new()
+            .chain_update(seed.to_le_bytes())
+            .chain_update(counter.to_le_bytes())
+            .finalize();
+        // Almost every digest is a valid scalar; retry on the rare miss.
+        if let Ok(key) = SigningKey::from_bytes(&digest) {
+            return Wallet::from_signing_key(key);
+        }
+        counter += 1;
+    }
+}
+
+/// A fresh chain with `blocks` blocks mined on top of genesis, all rewards
+/// going to the wallet derived from `seed`.
+pub fn mined_chain(blocks: usize, seed: u64) -> (Blockchain, Wallet) {
+    let miner = seeded_wallet(seed);
+    let mut chain = Blockchain::new().expect("creating a chain never fails");
+    for _ in 0..blocks {
+        chain
+            .mine_pending_transactions(PublicKey(miner.public_key))
+            .expect("mining an empty mempool never fails");
+    }
+    (chain, miner)
+}
+
+/// A chain plus a wallet holding at least `amount` coins, earned by mining
+/// as many blocks as needed.
+pub fn funded_wallet(amount: u64) -> (Blockchain, Wallet) {
+    let blocks = amount.div_ceil(MINING_REWARD).max(1);
+    mined_chain(blocks as usize, 0)
+}
+
+/// A transaction from `from` to `to`, signed by `from` for `chain`'s network
+/// with `from`'s next nonce on it.
+pub fn signed_tx(chain: &Blockchain, from: &Wallet, to: &Wallet, amount: u64) -> Transaction {
+    Transaction::builder(PublicKey(to.public_key), amount)
+        .network(&chain.network)
+        .nonce(chain.next_nonce(&PublicKey(from.public_key)))
+        .sign(from)
+}
+
+/// `tx` with its signature's `s` negated: the copy still verifies, but
+/// has a different txid. For recoverable signatures the recovery id is
+/// flipped too, so the same sender is recovered.
+pub fn malleated(tx: &Transaction) -> Transaction {
+    let signature = tx.signature.expect("only signed transactions can be malleated");
+    let (r, s) = signature.split_scalars();
+    let mut copy = tx.clone();
+    copy.signature = Some(Signature::from_scalars(r, -s).expect("negating s keeps it non-zero"));
+    copy.recovery_id = tx.recovery_id.map(|recovery_id| recovery_id ^ 1);
+    copy
+}
+
+/// Serializes `tx` to JSON and back, failing unless the result is equal.
+/// Guards the hand-written key and signature serialization.
+pub fn validate_roundtrip(tx: &Transaction) -> Result<()> {
+    roundtrip(tx, "transaction")
+}
+
+/// Like `validate_roundtrip`, for a block and every transaction in it.
+pub fn validate_block_roundtrip(block: &Block) -> Result<()> {
+    roundtrip(block, "block")
+}
+
+/// Like `validate_roundtrip`, for a wallet's private and public keys.
+pub fn validate_wallet_roundtrip(wallet: &Wallet) -> Result<()> {
+    roundtrip(wallet, "wallet")
+}
+
+/// Like `validate_roundtrip`, for a whole chain file: blocks, mempool and
+/// settings. Compared as JSON, since chains have no notion of equality.
+pub fn validate_chain_roundtrip(chain: &Blockchain) -> Result<()> {
+    let json = serde_json::to_value(chain)?;
+    let decoded: Blockchain = serde_json::from_value(json.clone())?;
+    if serde_json::to_value(&decoded)? != json {
+        bail!("The chain changed after a JSON round trip.");
+    }
+    Ok(())
+}
+
+fn roundtrip<T: Serialize + DeserializeOwned + PartialEq>(value: &T, what: &str) -> Result<()> {
+    let json = serde_json::to_string(value)?;
+    let decoded: T = serde_json::from_str(&json)?;
+    if decoded != *value {
+        bail!("The {} changed after a JSON round trip.", what);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::VersionActivation;
+    use crate::transaction::SpendCondition;
+    use crate::wallet::StoredWallet;
+
+    #[test]
+    fn seeded_wallets_are_deterministic() {
+        assert_eq!(seeded_wallet(7), seeded_wallet(7));
+        assert_ne!(seeded_wallet(7).public_key, seeded_wallet(8).public_key);
+    }
+
+    #[test]
+    fn mined_chain_pays_every_reward_to_the_miner() {
+        let (chain, miner) = mined_chain(3, 1);
+        assert_eq!(chain.chain.len(), 4);
+        assert_eq!(chain.get_balance(&PublicKey(miner.public_key)).unwrap(), 3 * MINING_REWARD as i64);
+        assert!(chain.is_chain_valid());
+    }
+
+    #[test]
+    fn funded_wallet_holds_at_least_the_amount() {
+        let amount = MINING_REWARD + 1;
+        let (chain, wallet) = funded_wallet(amount);
+        assert!(chain.get_balance(&PublicKey(wallet.public_key)).unwrap() >= amount as i64);
+    }
+
+    #[test]
+    fn signed_txs_are_accepted_and_take_consecutive_nonces() {
+        let (mut chain, payer) = funded_wallet(50);
+        let payee = seeded_wallet(1);
+        let first = signed_tx(&chain, &payer, &payee, 10);
+        chain.add_transaction(first.clone()).unwrap();
+        let second = signed_tx(&chain, &payer, &payee, 15);
+        assert_eq!((first.nonce, second.nonce), (Some(0), Some(1)));
+        chain.add_transaction(second).unwrap();
+        chain.mine_pending_transactions(PublicKey(payer.public_key)).unwrap();
+        assert_eq!(chain.get_balance(&PublicKey(payee.public_key)).unwrap(), 25);
+        assert!(chain.is_chain_valid());
+    }
+
+    /// Payments from `from` with every combination of optional fields.
+    fn varied_txs(chain: &Blockchain, from: &Wallet) -> Vec<Transaction> {
+        let to = PublicKey(seeded_wallet(9).public_key);
+        let nonce = chain.next_nonce(&PublicKey(from.public_key));
+        vec![
+            Transaction::builder(to.clone(), 1).sign(from),
+            Transaction::new(from, to.clone(), 2, nonce),
+            Transaction::builder(to.clone(), 3)
+                .condition(SpendCondition::Timelock { height: 50 })
+                .locktime(3)
+                .network(&chain.network)
+                .nonce(nonce + 1)
+                .sign(from),
+            Transaction::builder(to.clone(), 4).recoverable().network(&chain.network).nonce(nonce + 2).sign(from),
+            Transaction::builder(to, 5).nonce(nonce + 3).network("test").sign(from),
+        ]
+    }
+
+    #[test]
+    fn transactions_roundtrip_with_optional_fields() {
+        let (chain, payer) = funded_wallet(50);
+        for tx in varied_txs(&chain, &payer) {
+            validate_roundtrip(&tx).unwrap();
+            let decoded: Transaction = serde_json::from_str(&serde_json::to_string(&tx).unwrap()).unwrap();
+            assert!(decoded.is_valid());
+            assert_eq!(decoded.txid(), tx.txid());
+        }
+        validate_roundtrip(&Transaction::new_coinbase(PublicKey(payer.public_key), MINING_REWARD)).unwrap();
+    }
+
+    #[test]
+    fn blocks_roundtrip_in_both_versions() {
+        let (chain, payer) = funded_wallet(50);
+        let mut transactions = vec![Transaction::new_coinbase(PublicKey(payer.public_key), MINING_REWARD)];
+        transactions.extend(varied_txs(&chain, &payer));
+        let mut block = Block::new(2, transactions, chain.chain[1].hash.clone(), 1);
+        block.mine();
+        validate_block_roundtrip(&block).unwrap();
+
+        block.version = 1;
+        block.merkle_root.clear();
+        block.mine();
+        validate_block_roundtrip(&block).unwrap();
+        let json = serde_json::to_value(&block).unwrap();
+        assert!(json.get("merkle_root").is_none());
+        let decoded: Block = serde_json::from_value(json).unwrap();
+        assert!(decoded.verify_hash());
+    }
+
+    #[test]
+    fn chain_file_roundtrips_with_mempool_and_settings() {
+        let mut chain = Blockchain::new_on_network("test").unwrap();
+        let miner = seeded_wallet(4);
+        chain.mine_pending_transactions(PublicKey(miner.public_key)).unwrap();
+        for tx in varied_txs(&chain, &miner).into_iter().skip(2).take(2) {
+            chain.add_transaction(tx).unwrap();
+        }
+        chain.idempotency_keys.insert("sender:key".to_string(), "txid".to_string());
+        chain.version_activation = Some(VersionActivation {
+            height: 10,
+            min_block_version: 3,
+        });
+        validate_chain_roundtrip(&chain).unwrap();
+    }
+
+    #[test]
+    fn wallet_files_roundtrip_plain_and_encrypted() {
+        let wallet = seeded_wallet(5);
+        validate_wallet_roundtrip(&wallet).unwrap();
+        roundtrip(&StoredWallet::Plain(wallet.clone()), "wallet file").unwrap();
+        let encrypted = StoredWallet::Encrypted(wallet.encrypt("passphrase").unwrap());
+        roundtrip(&encrypted, "wallet file").unwrap();
+        let json = serde_json::to_vec(&encrypted).unwrap();
+        assert_eq!(StoredWallet::from_slice(&json).unwrap(), encrypted);
+    }
+
+    #[test]
+    fn roundtrip_validators_accept_fixtures() {
+        let (mut chain, payer) = funded_wallet(50);
+        chain.add_transaction(signed_tx(&chain, &payer, &seeded_wallet(1), 10)).unwrap();
+        chain.mine_pending_transactions(PublicKey(payer.public_key)).unwrap();
+        let block = chain.chain.last().unwrap();
+        validate_block_roundtrip(block).unwrap();
+        block.transactions.iter().try_for_each(validate_roundtrip).unwrap();
+        validate_wallet_roundtrip(&payer).unwrap();
+    }
+}
@@ -1,12 +1,29 @@
-use anyhow::Result;
-use core::convert::TryFrom;
-use p256::ecdsa::{signature::hazmat::PrehashSigner, Signature, SigningKey, VerifyingKey};
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use ecdsa::RecoveryId;
+use p256::ecdsa::signature::hazmat::{PrehashSigner, PrehashVerifier};
+use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
 use p256::elliptic_curve::consts::U32;
 use p256::elliptic_curve::generic_array::GenericArray;
 use rand::rngs::OsRng;
+use rand::RngCore;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// scrypt cost for new encrypted wallets: N = 2^15, r = 8, p = 1, the
+/// commonly recommended interactive-login setting.
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+/// Starts every key written by `Wallet::export_key`, so it's recognisable
+/// as a private key and can't be mistaken for an address.
+pub const EXPORTED_KEY_PREFIX: &str = "mbkey";
+/// Format version byte of exported keys.
+const EXPORTED_KEY_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Wallet {
     #[serde(serialize_with = "serialize_key", deserialize_with = "deserialize_key")]
     signing_key: SigningKey,
@@ -15,7 +32,10 @@ pub struct Wallet {
 
 impl Wallet {
     pub fn new() -> Self {
-        let signing_key = SigningKey::random(&mut OsRng);
+        Self::from_signing_key(SigningKey::random(&mut OsRng))
+    }
+
+    pub fn from_signing_key(signing_key: SigningKey) -> Self {
         let public_key = *signing_key.verifying_key();
         Wallet {
             signing_key,
@@ -23,9 +43,212 @@ impl Wallet {
         }
     }
 
+    /// Rebuilds a wallet from the hex private key stored in wallet files,
+    /// deriving the public key from it.
+    pub fn from_private_key_hex(hex_str: &str) -> Result<Self> {
+        Ok(Self::from_signing_key(parse_signing_key(hex_str)?))
+    }
+
+    /// Signs `hash`, always returning the low-S form of the signature (see
+    /// `Transaction::has_low_s`).
     pub fn sign_prehashed(&self, hash: &[u8]) -> Signature {
-        self.signing_key.sign_prehash(hash).unwrap()
+        let signature: Signature = self.signing_key.sign_prehash(hash).unwrap();
+        signature.normalize_s().unwrap_or(signature)
+    }
+
+    /// Checks the stored public key belongs to the private key and that the
+    /// pair can sign and verify, e.g. to diagnose a corrupted wallet file.
+    pub fn verify(&self) -> Result<()> {
+        if *self.signing_key.verifying_key() != self.public_key {
+            bail!("The stored public key doesn't belong to the private key.");
+        }
+        let digest = Sha256::digest(b"mini-blockchain wallet check");
+        let signature: Signature = self.signing_key.sign_prehash(&digest)?;
+        if self.public_key.verify_prehash(&digest, &signature).is_err() {
+            bail!("A test signature made with the private key didn't verify.");
+        }
+        Ok(())
+    }
+
+    /// Like `sign_prehashed`, plus the id needed to recover the public key
+    /// from the signature.
+    pub fn sign_prehashed_recoverable(&self, hash: &[u8]) -> (Signature, RecoveryId) {
+        let (signature, recovery_id) = self.signing_key.sign_prehash_recoverable(hash).unwrap();
+        match signature.normalize_s() {
+            // Negating s recovers the same key from the other candidate point.
+            Some(low) => (low, RecoveryId::new(!recovery_id.is_y_odd(), recovery_id.is_x_reduced())),
+            None => (signature, recovery_id),
+        }
+    }
+
+    /// The private key as one portable line: `EXPORTED_KEY_PREFIX`, then hex
+    /// of a version byte, the 32-byte key and a 4-byte checksum (the start
+    /// of the double SHA-256 of the version and key).
+    pub fn export_key(&self) -> String {
+        let mut payload = vec![EXPORTED_KEY_VERSION];
+        payload.extend_from_slice(&self.signing_key.to_bytes());
+        let checksum = export_checksum(&payload);
+        payload.extend_from_slice(&checksum);
+        format!("{}{}", EXPORTED_KEY_PREFIX, hex::encode(payload))
+    }
+
+    /// Rebuilds a wallet from a key written by `export_key`, checking its
+    /// prefix, version and checksum first.
+    pub fn from_exported_key(exported: &str) -> Result<Self> {
+        let hex_str = exported
+            .trim()
+            .strip_prefix(EXPORTED_KEY_PREFIX)
+            .with_context(|| format!("An exported key starts with '{}'.", EXPORTED_KEY_PREFIX))?;
+        let payload = hex::decode(hex_str).context("The exported key isn't valid hex after its prefix.")?;
+        if payload.len() != 37 {
+            bail!("The exported key is {} bytes long, but should be 37. Was it cut off?", payload.len());
+        }
+        let (body, checksum) = payload.split_at(33);
+        if checksum != export_checksum(body) {
+            bail!("The exported key's checksum doesn't match. Check it was copied exactly.");
+        }
+        if body[0] != EXPORTED_KEY_VERSION {
+            bail!("The exported key is version {}, which this build can't read.", body[0]);
+        }
+        Ok(Self::from_signing_key(SigningKey::from_slice(&body[1..])?))
     }
+
+    /// Encrypts the private key under `passphrase`, with a fresh salt and nonce.
+    pub fn encrypt(&self, passphrase: &str) -> Result<EncryptedWallet> {
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+        let mut encrypted_key = EncryptedKey {
+            kdf: "scrypt".to_string(),
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: String::new(),
+        };
+        let ciphertext = encrypted_key
+            .cipher(passphrase)?
+            .encrypt(
+                (&nonce).into(),
+                Payload {
+                    msg: &self.signing_key.to_bytes(),
+                    aad: &self.public_key.to_sec1_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Encrypting the private key failed."))?;
+        encrypted_key.ciphertext = hex::encode(ciphertext);
+        Ok(EncryptedWallet {
+            public_key: self.public_key,
+            encrypted_key,
+        })
+    }
+}
+
+/// A wallet file whose private key is encrypted with a passphrase. The public
+/// key stays readable, so the wallet's address can be shown without it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptedWallet {
+    pub public_key: VerifyingKey,
+    pub encrypted_key: EncryptedKey,
+}
+
+/// A private key sealed with ChaCha20-Poly1305 under a key derived from the
+/// passphrase with scrypt. The public key is authenticated with it, so
+/// swapping in another address makes decryption fail.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptedKey {
+    pub kdf: String,
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+    pub salt: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+impl EncryptedKey {
+    fn cipher(&self, passphrase: &str) -> Result<ChaCha20Poly1305> {
+        if self.kdf != "scrypt" {
+            bail!("The wallet is encrypted with '{}', which this build doesn't support.", self.kdf);
+        }
+        let params = scrypt::Params::new(self.log_n, self.r, self.p, 32)
+            .map_err(|err| anyhow!("The wallet's scrypt settings are invalid: {}.", err))?;
+        let salt = hex::decode(&self.salt).context("The wallet's salt isn't valid hex.")?;
+        let mut key = [0u8; 32];
+        scrypt::scrypt(passphrase.as_bytes(), &salt, &params, &mut key)
+            .map_err(|err| anyhow!("Deriving the wallet key failed: {}.", err))?;
+        Ok(ChaCha20Poly1305::new(&key.into()))
+    }
+}
+
+impl EncryptedWallet {
+    /// Decrypts the private key, failing on a wrong passphrase or a file
+    /// that was tampered with.
+    pub fn decrypt(&self, passphrase: &str) -> Result<Wallet> {
+        let nonce = hex::decode(&self.encrypted_key.nonce).context("The wallet's nonce isn't valid hex.")?;
+        if nonce.len() != 12 {
+            bail!("The wallet's nonce is {} bytes, not 12.", nonce.len());
+        }
+        let ciphertext = hex::decode(&self.encrypted_key.ciphertext)
+            .context("The wallet's encrypted key isn't valid hex.")?;
+        let key_bytes = self
+            .encrypted_key
+            .cipher(passphrase)?
+            .decrypt(
+                nonce.as_slice().into(),
+                Payload {
+                    msg: &ciphertext,
+                    aad: &self.public_key.to_sec1_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Wrong passphrase, or the wallet file has been altered."))?;
+        let wallet = Wallet::from_signing_key(SigningKey::from_slice(&key_bytes)?);
+        if wallet.public_key != self.public_key {
+            bail!("The decrypted private key doesn't belong to the wallet's public key.");
+        }
+        Ok(wallet)
+    }
+}
+
+/// A wallet file as stored: plain, or with its private key encrypted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StoredWallet {
+    Encrypted(EncryptedWallet),
+    Plain(Wallet),
+}
+
+impl StoredWallet {
+    /// Parses a wallet file, telling the two formats apart by whether it has
+    /// an `encrypted_key`, so a damaged file reports what's wrong with it.
+    pub fn from_slice(data: &[u8]) -> Result<Self> {
+        let document: serde_json::Value = serde_json::from_slice(data)?;
+        if document.get("encrypted_key").is_some() {
+            Ok(StoredWallet::Encrypted(serde_json::from_value(document)?))
+        } else {
+            Ok(StoredWallet::Plain(serde_json::from_value(document)?))
+        }
+    }
+
+    pub fn public_key(&self) -> VerifyingKey {
+        match self {
+            StoredWallet::Encrypted(wallet) => wallet.public_key,
+            StoredWallet::Plain(wallet) => wallet.public_key,
+        }
+    }
+}
+
+impl Default for Wallet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn export_checksum(body: &[u8]) -> [u8; 4] {
+    let digest = Sha256::digest(Sha256::digest(body));
+    [digest[0], digest[1], digest[2], digest[3]]
 }
 
 fn serialize_key<S>(key: &SigningKey, serializer: S) -> Result<S::Ok, S::Error>
@@ -41,14 +264,121 @@ where
 {
     use serde::de::Error;
     let hex_str = String::deserialize(deserializer)?;
-    let bytes = hex::decode(hex_str).map_err(Error::custom)?;
+    parse_signing_key(&hex_str).map_err(Error::custom)
+}
+
+fn parse_signing_key(hex_str: &str) -> Result<SigningKey> {
+    let bytes = hex::decode(hex_str)?;
 
-    let key_bytes = <&GenericArray<u8, U32>>::try_from(&bytes[..]).map_err(|_| {
-        Error::custom(format!(
+    if bytes.len() != 32 {
+        bail!(
             "This doesn't look like a valid 32-byte private key. Length was {}.",
             bytes.len()
-        ))
-    })?;
+        );
+    }
+    let key_bytes = GenericArray::<u8, U32>::from_slice(&bytes);
+
+    Ok(SigningKey::from_bytes(key_bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypted_wallet_decrypts_with_its_passphrase() {
+        let wallet = Wallet::new();
+        let encrypted = wallet.encrypt("hunter2").unwrap();
+        assert_eq!(encrypted.public_key, wallet.public_key);
+        assert!(!encrypted.encrypted_key.ciphertext.contains(&hex::encode(wallet.signing_key.to_bytes())));
+        assert_eq!(encrypted.decrypt("hunter2").unwrap(), wallet);
+    }
 
-    SigningKey::from_bytes(key_bytes).map_err(Error::custom)
-}
\ No newline at end of file
+    #[test]
+    fn wrong_passphrase_is_refused() {
+        let encrypted = Wallet::new().encrypt("hunter2").unwrap();
+        for passphrase in ["hunter3", "", "Hunter2"] {
+            assert!(encrypted.decrypt(passphrase).is_err(), "{:?}", passphrase);
+        }
+    }
+
+    #[test]
+    fn altered_encrypted_wallets_are_refused() {
+        let encrypted = Wallet::new().encrypt("hunter2").unwrap();
+
+        let mut ciphertext = encrypted.clone();
+        let last = ciphertext.encrypted_key.ciphertext.pop().unwrap();
+        ciphertext.encrypted_key.ciphertext.push(if last == '0' { '1' } else { '0' });
+        assert!(ciphertext.decrypt("hunter2").is_err());
+
+        let mut address = encrypted.clone();
+        address.public_key = Wallet::new().public_key;
+        assert!(address.decrypt("hunter2").is_err());
+
+        let mut salt = encrypted;
+        salt.encrypted_key.salt = hex::encode([0u8; 16]);
+        assert!(salt.decrypt("hunter2").is_err());
+    }
+
+    #[test]
+    fn exported_key_is_prefixed_versioned_and_checksummed() {
+        let wallet = Wallet::new();
+        let exported = wallet.export_key();
+        let payload = hex::decode(exported.strip_prefix(EXPORTED_KEY_PREFIX).unwrap()).unwrap();
+        assert_eq!(payload.len(), 37);
+        assert_eq!(payload[0], EXPORTED_KEY_VERSION);
+        assert_eq!(&payload[1..33], wallet.signing_key.to_bytes().as_slice());
+        assert_eq!(payload[33..], export_checksum(&payload[..33]));
+        assert_eq!(exported, wallet.export_key());
+    }
+
+    #[test]
+    fn exported_key_imports_as_the_same_wallet() {
+        let wallet = Wallet::new();
+        let exported = wallet.export_key();
+        assert_eq!(Wallet::from_exported_key(&exported).unwrap(), wallet);
+        assert_eq!(Wallet::from_exported_key(&format!("  {}\n", exported)).unwrap(), wallet);
+    }
+
+    #[test]
+    fn damaged_exported_keys_are_refused() {
+        let exported = Wallet::new().export_key();
+        let flip = |position: usize| {
+            let mut chars: Vec<char> = exported.chars().collect();
+            chars[position] = if chars[position] == 'a' { 'b' } else { 'a' };
+            chars.into_iter().collect::<String>()
+        };
+        let mut rejected = vec![
+            flip(EXPORTED_KEY_PREFIX.len() + 10),
+            flip(exported.len() - 1),
+            exported[EXPORTED_KEY_PREFIX.len()..].to_string(),
+            exported[..exported.len() - 2].to_string(),
+            format!("{}00", exported),
+            exported.replace(EXPORTED_KEY_PREFIX, "mbkex"),
+            format!("{}zz", &exported[..exported.len() - 2]),
+        ];
+
+        // A correct checksum over an unknown version.
+        let mut body = hex::decode(&exported[EXPORTED_KEY_PREFIX.len()..]).unwrap();
+        body.truncate(33);
+        body[0] = EXPORTED_KEY_VERSION + 1;
+        let checksum = export_checksum(&body);
+        body.extend(checksum);
+        rejected.push(format!("{}{}", EXPORTED_KEY_PREFIX, hex::encode(body)));
+
+        for key in rejected {
+            assert!(Wallet::from_exported_key(&key).is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn stored_wallets_are_told_apart_by_their_encrypted_key() {
+        let wallet = Wallet::new();
+        let plain = serde_json::to_vec(&wallet).unwrap();
+        assert_eq!(StoredWallet::from_slice(&plain).unwrap(), StoredWallet::Plain(wallet.clone()));
+        let encrypted = serde_json::to_vec(&wallet.encrypt("hunter2").unwrap()).unwrap();
+        let stored = StoredWallet::from_slice(&encrypted).unwrap();
+        assert!(matches!(stored, StoredWallet::Encrypted(_)));
+        assert_eq!(stored.public_key(), wallet.public_key);
+    }
+}